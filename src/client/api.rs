@@ -1,13 +1,18 @@
-use cluster::host::{Host, HostId, HostState};
+use cluster::host::{Host, HostId};
 use cluster::invocation::{Invocation, InvocationId};
+use cluster::report::{HostReport, PhaseReport};
+use cluster::telemetry::Heartbeat;
+use cluster::upload::{UploadId, UploadStatus};
 
-use reqwest::multipart;
+use reqwest::{Method, RequestBuilder};
 
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::path::Path;
+use std::time;
 
 #[derive(Deserialize)]
 enum Status {
@@ -100,66 +105,185 @@ impl ResponseError {
     }
 }
 
-pub struct Connector(String);
+pub struct Connector {
+    base: String,
+    /// The enrolment token presented to the server with every request, if it requires one.
+    token: Option<String>,
+}
 
 impl<'a> Connector {
-    pub fn new(server: &str, port: u16) -> Connector {
-        Connector(format!("http://{}:{}/api/", server, port))
+    pub fn new(server: &str, port: u16, token: Option<String>) -> Connector {
+        Connector {
+            base: format!("http://{}:{}/api/v2/", server, port),
+            token,
+        }
     }
 
-    pub fn status(&self, id: HostId, state: HostState) -> Result<(), ResponseError> {
-        let state = match state {
-            HostState::Idle => "idle".to_string(),
-            HostState::Running { id } => format!("running/{}", id),
-            HostState::Errored { id } => format!("errored/{}", id),
-            HostState::Compressing { id } => format!("compressing/{}", id),
-            HostState::Uploading { id } => format!("uploading/{}", id),
-            HostState::Done { id } => format!("done/{}", id),
-            _ => unreachable!(),
-        };
-        self.check(&format!("host/status/{}/{}", id, state))
+    /// Sends a heartbeat, returning whether the host has been drained and should abandon the
+    /// invocation it is running.
+    pub fn heartbeat(&self, id: HostId, heartbeat: &Heartbeat) -> Result<bool, ResponseError> {
+        self.exchange(Method::PUT, &format!("hosts/{}/heartbeat", id), heartbeat)
     }
 
-    pub fn register(&self, hostname: &str) -> Result<Host, ResponseError> {
-        self.get::<Host>(&format!("host/register/{}", hostname))
+    /// Registers the host, advertising the labels it was given and the facts detected about it.
+    pub fn register(
+        &self,
+        hostname: &str,
+        labels: &BTreeMap<String, String>,
+        facts: &BTreeMap<String, String>,
+    ) -> Result<Host, ResponseError> {
+        self.exchange::<_, Host>(
+            Method::POST,
+            "hosts",
+            &serde_json::json!({ "hostname": hostname, "labels": labels, "facts": facts }),
+        )
     }
 
     pub fn current(&self) -> Result<InvocationId, ResponseError> {
         self.get::<InvocationId>("current")
     }
 
+    /// Waits until the current invocation is something other than `seen`, or until the timeout
+    /// passes, returning whether it changed. Servers without long-polling (or too busy to wait)
+    /// return an error, in which case the client should poll instead.
+    pub fn wait(
+        &self,
+        seen: Option<InvocationId>,
+        timeout: time::Duration,
+    ) -> Result<bool, ResponseError> {
+        let mut target = format!("current/wait?timeout={}", timeout.as_secs());
+        if let Some(id) = seen {
+            target.push_str(&format!("&since={}", id));
+        }
+        self.get::<bool>(&target)
+    }
+
     pub fn invocation(&self, id: InvocationId) -> Result<Invocation, ResponseError> {
         self.get::<Invocation>(&format!("invocation/{}", id))
     }
 
-    pub fn upload<P: AsRef<Path>>(
+    pub fn report(
         &self,
-        path: P,
         id: InvocationId,
         host: HostId,
+        report: &HostReport,
     ) -> Result<(), ResponseError> {
-        multipart::Form::new()
-            .file("log", path)
+        self.send(Method::POST, &format!("report/{}/{}", id, host), report)
+    }
+
+    pub fn report_phase(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        report: &PhaseReport,
+    ) -> Result<(), ResponseError> {
+        self.send(
+            Method::POST,
+            &format!("report/{}/{}/phase", id, host),
+            report,
+        )
+    }
+
+    /// Arrives at the named barrier, returning whether it has been released.
+    pub fn barrier(
+        &self,
+        id: InvocationId,
+        name: &str,
+        host: HostId,
+    ) -> Result<bool, ResponseError> {
+        self.post_raw(&format!("barrier/{}/{}/{}", id, name, host), vec![])
+    }
+
+    /// Starts a chunked upload of a log archive of the given size and hex-encoded SHA-256 digest.
+    pub fn begin_upload(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        size: u64,
+        checksum: &str,
+    ) -> Result<UploadStatus, ResponseError> {
+        self.post_raw(
+            &format!(
+                "uploads/{}/{}?size={}&checksum={}",
+                id, host, size, checksum
+            ),
+            vec![],
+        )
+    }
+
+    pub fn upload_status(&self, upload: UploadId) -> Result<UploadStatus, ResponseError> {
+        self.get::<UploadStatus>(&format!("uploads/{}", upload))
+    }
+
+    /// Sends a chunk of a log archive, starting at the given offset.
+    pub fn upload_chunk(
+        &self,
+        upload: UploadId,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<UploadStatus, ResponseError> {
+        self.post_raw(&format!("uploads/{}?offset={}", upload, offset), chunk)
+    }
+
+    /// Sends a chunk of a streamed file, starting at the given offset, returning how much of the
+    /// file the server now has.
+    pub fn stream(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        name: &Path,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<u64, ResponseError> {
+        self.post_raw(
+            &format!(
+                "stream/{}/{}/{}?offset={}",
+                id,
+                host,
+                name.display(),
+                offset
+            ),
+            chunk,
+        )
+    }
+
+    fn get<T: DeserializeOwned>(&self, target: &str) -> Result<T, ResponseError> {
+        self.request(Method::GET, target)
+            .send()
+            .and_then(|mut response| response.json::<Response<T>>())
             .map_err(|err| ResponseError {
                 cause: Some(Box::new(err)),
                 kind: ResponseErrorKind::RequestFailed,
             })
-            .and_then(|form| {
-                reqwest::Client::new()
-                    .post(&format!("{}upload/{}/{}", &self.0, id, host))
-                    .multipart(form)
-                    .send()
-                    .and_then(|mut response| response.json::<EmptyResponse>())
-                    .map_err(|err| ResponseError {
-                        cause: Some(Box::new(err)),
-                        kind: ResponseErrorKind::RequestFailed,
-                    })
-                    .and_then(|response| response.into_result())
+            .and_then(|response| response.into_result())
+    }
+
+    fn send<B: Serialize>(
+        &self,
+        method: Method,
+        target: &str,
+        body: &B,
+    ) -> Result<(), ResponseError> {
+        self.request(method, target)
+            .json(body)
+            .send()
+            .and_then(|mut response| response.json::<EmptyResponse>())
+            .map_err(|err| ResponseError {
+                cause: Some(Box::new(err)),
+                kind: ResponseErrorKind::RequestFailed,
             })
+            .and_then(|response| response.into_result())
     }
 
-    fn get<T: DeserializeOwned>(&self, target: &str) -> Result<T, ResponseError> {
-        reqwest::get(&format!("{}{}", self.0, target))
+    fn exchange<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        target: &str,
+        body: &B,
+    ) -> Result<T, ResponseError> {
+        self.request(method, target)
+            .json(body)
+            .send()
             .and_then(|mut response| response.json::<Response<T>>())
             .map_err(|err| ResponseError {
                 cause: Some(Box::new(err)),
@@ -168,13 +292,29 @@ impl<'a> Connector {
             .and_then(|response| response.into_result())
     }
 
-    fn check(&self, target: &str) -> Result<(), ResponseError> {
-        reqwest::get(&format!("{}{}", self.0, target))
-            .and_then(|mut response| response.json::<EmptyResponse>())
+    fn post_raw<T: DeserializeOwned>(
+        &self,
+        target: &str,
+        body: Vec<u8>,
+    ) -> Result<T, ResponseError> {
+        self.request(Method::POST, target)
+            .body(body)
+            .send()
+            .and_then(|mut response| response.json::<Response<T>>())
             .map_err(|err| ResponseError {
                 cause: Some(Box::new(err)),
                 kind: ResponseErrorKind::RequestFailed,
             })
             .and_then(|response| response.into_result())
     }
+
+    /// Starts building a request to the API, authenticated if the client has a token. A new HTTP
+    /// client is created for every request, as requests are also made from forked children.
+    fn request(&self, method: Method, target: &str) -> RequestBuilder {
+        let request = reqwest::Client::new().request(method, &format!("{}{}", self.base, target));
+        match self.token {
+            Some(ref token) => request.header("Authorization", format!("Bearer {}", token)),
+            None => request,
+        }
+    }
 }
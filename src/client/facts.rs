@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::{env, fs};
+
+/// What can be detected about this host, for experiments to select hosts by. Memory is given in
+/// MiB. Anything that can't be detected is left out.
+pub fn detect() -> BTreeMap<String, String> {
+    let mut facts = BTreeMap::new();
+    facts.insert("arch".to_string(), env::consts::ARCH.to_string());
+    facts.insert("os".to_string(), env::consts::OS.to_string());
+    facts.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    if let Ok(cpuinfo) = fs::read_to_string("/proc/cpuinfo") {
+        let cores = cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("processor"))
+            .count();
+        facts.insert("cores".to_string(), cores.to_string());
+        if let Some(model) = field(&cpuinfo, "model name") {
+            facts.insert("cpu_model".to_string(), model);
+        }
+    }
+    if let Some(memory) = fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| field(&meminfo, "MemTotal"))
+        .and_then(|total| total.trim_end_matches("kB").trim().parse::<u64>().ok())
+    {
+        facts.insert("memory".to_string(), (memory / 1024).to_string());
+    }
+    if let Ok(kernel) = fs::read_to_string("/proc/sys/kernel/osrelease") {
+        facts.insert("kernel".to_string(), kernel.trim().to_string());
+    }
+    facts
+}
+
+/// Reads labels from a TOML file of `key = value` pairs, if given, then from `key=value` flags,
+/// which take precedence.
+pub fn labels(
+    file: Option<&Path>,
+    flags: &[&str],
+) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut labels = BTreeMap::new();
+    if let Some(file) = file {
+        let table = fs::read_to_string(file)?.parse::<toml::Value>()?;
+        let table = table
+            .as_table()
+            .ok_or_else(|| format!("{} is not a table of labels", file.display()))?;
+        for (key, value) in table.iter() {
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                value => format!("{}", value),
+            };
+            labels.insert(key.clone(), value);
+        }
+    }
+    for flag in flags {
+        let mut parts = flag.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => {
+                labels.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => return Err(format!("label {} is not of the form key=value", flag).into()),
+        }
+    }
+    Ok(labels)
+}
+
+/// The value of the first `name: value` line in a file from `/proc`.
+fn field(contents: &str, name: &str) -> Option<String> {
+    contents
+        .lines()
+        .find(|line| line.starts_with(name))
+        .and_then(|line| line.splitn(2, ':').nth(1))
+        .map(|value| value.trim().to_string())
+}
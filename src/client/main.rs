@@ -3,15 +3,21 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
-use api::Connector;
+use api::{Connector, ResponseError};
+use spool::Spool;
+use stream::{Sources, Streamer};
+use usage::Recorder;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 
 use clap::{App, Arg};
 
-use cluster::descriptor::ExperimentDescriptor;
-use cluster::host::{Host, HostState};
+use cluster::descriptor::{ExperimentDescriptor, Observer, GRACE_DEFAULT};
+use cluster::host::{Host, HostId, HostState};
 use cluster::invocation::{Invocation, InvocationId, InvocationRecord};
+use cluster::process::{groups_in, signal_session};
+use cluster::report::{HostReport, PhaseReport, Termination};
+use cluster::telemetry::{Heartbeat, Telemetry};
 
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -19,18 +25,39 @@ use flate2::Compression;
 use git2::Repository;
 
 use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet};
-use nix::unistd::{fork, setpgid, ForkResult, Pid};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, setsid, ForkResult, Pid};
 
 use rand::Rng;
 
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::{self, File};
+use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::{cmp, fmt, mem, process, thread, time};
+use std::{cmp, env, fmt, mem, process, thread, time};
 
 mod api;
+mod facts;
+mod spool;
+mod stream;
+mod usage;
+
+/// How often to check whether a barrier has been released.
+const BARRIER_INTERVAL: time::Duration = time::Duration::from_millis(50);
+/// How often to check whether a terminated child process has exited.
+const EXIT_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// How often the server is polled for the current invocation when it can't be waited on, and the
+/// longest a running experiment goes unchecked.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(2000);
+/// The longest to wait for the current invocation to change in a single request (which must be
+/// less than the HTTP client's 30 second timeout).
+const LONG_POLL: time::Duration = time::Duration::from_secs(25);
+/// How often the host is sampled for telemetry to send with its heartbeat.
+const TELEMETRY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// By default, how many seconds apart the resources used by an experiment are sampled.
+const USAGE_INTERVAL_DEFAULT: u64 = 1;
 
 struct Client {
     path: PathBuf,
@@ -38,13 +65,33 @@ struct Client {
     host: Arc<RwLock<Host>>,
     executor: Option<Executor>,
     history: Option<Executor>,
+    /// The current invocation when the server was last polled.
+    seen: Option<InvocationId>,
+    /// Set when the server asks the host to abandon the invocation it is running.
+    drained: Arc<AtomicBool>,
+    /// How long a cancelled experiment is given to exit after SIGTERM before it is sent SIGKILL.
+    grace: time::Duration,
+    /// How often the resources used by an experiment are sampled, if at all.
+    usage_interval: Option<time::Duration>,
+    spool: Spool,
 }
 
 struct Executor {
     pid: Pid,
+    start: DateTime<Utc>,
     descriptor: ExperimentDescriptor,
     invocation: InvocationRecord,
     repo: Repository,
+    log: String,
+    streamer: Option<Streamer>,
+    recorder: Option<Recorder>,
+}
+
+/// Relays the progress of an invocation to the server from the forked child.
+struct Reporter<'a> {
+    client: &'a Client,
+    invocation: &'a InvocationRecord,
+    host: HostId,
 }
 
 #[derive(Debug)]
@@ -65,11 +112,15 @@ enum ClientErrorKind {
     CompressionFailed,
     /// Couldn't upload the log archive for the current invocation.
     UploadFailed,
+    /// Couldn't open the directory in which log archives wait to be uploaded.
+    SpoolFailed,
     /// There was a failure while attempting to clone the repository.
     CloningFailed,
     /// The cloned repository has commits missing (i.e. previously valid references are no longer
     /// present).
     MissingCommits,
+    /// The labels given for the host couldn't be read.
+    InvalidLabels,
 }
 
 impl fmt::Display for ClientErrorKind {
@@ -93,6 +144,10 @@ impl fmt::Display for ClientErrorKind {
                 f,
                 "couldn't upload the log archive for the current invocation"
             ),
+            ClientErrorKind::SpoolFailed => write!(
+                f,
+                "couldn't open the directory in which log archives wait to be uploaded"
+            ),
             ClientErrorKind::CloningFailed => write!(
                 f,
                 "there was a failure while attempting to clone the repository"
@@ -100,6 +155,9 @@ impl fmt::Display for ClientErrorKind {
             ClientErrorKind::MissingCommits => {
                 write!(f, "the cloned repository has commits missing")
             }
+            ClientErrorKind::InvalidLabels => {
+                write!(f, "the labels given for the host couldn't be read")
+            }
         }
     }
 }
@@ -126,14 +184,26 @@ impl From<ClientErrorKind> for ClientError {
 }
 
 impl Client {
-    fn new<P: AsRef<Path>>(server: &str, port: u16, path: P) -> Result<Client, ClientError> {
-        let connector = Arc::new(Connector::new(server, port));
+    fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        connector: Connector,
+        labels: BTreeMap<String, String>,
+        path: P,
+        spool: Q,
+        grace: time::Duration,
+        usage_interval: Option<time::Duration>,
+    ) -> Result<Client, ClientError> {
+        let spool = Spool::open(spool).map_err(|err| ClientError {
+            cause: Some(Box::new(err)),
+            kind: ClientErrorKind::SpoolFailed,
+        })?;
+        let connector = Arc::new(connector);
+        let facts = facts::detect();
         let hostname = gethostname::gethostname()
             .into_string()
             .map_err(|_| ClientError::from(ClientErrorKind::NoHostname))?;
         info!("registering with server...");
         let host = Arc::new(RwLock::new(loop {
-            match connector.register(&hostname) {
+            match connector.register(&hostname, &labels, &facts) {
                 Ok(host) => {
                     info!("registered");
                     break host;
@@ -144,23 +214,44 @@ impl Client {
                 }
             }
         }));
+        let drained = Arc::new(AtomicBool::new(false));
         {
             let connector = Arc::clone(&connector);
             let host = Arc::clone(&host);
+            let drained = Arc::clone(&drained);
+            let work_dir = path.as_ref().to_path_buf();
             thread::spawn(move || {
                 let mut rng = rand::thread_rng();
+                let mut sampled: Option<time::Instant> = None;
                 loop {
                     thread::sleep(time::Duration::from_millis(500));
                     let (id, state) = {
                         let host = host.read().unwrap();
                         (host.id(), host.state())
                     };
+                    let telemetry = match sampled {
+                        Some(sampled) if sampled.elapsed() < TELEMETRY_INTERVAL => None,
+                        _ => {
+                            sampled = Some(time::Instant::now());
+                            Telemetry::sample(&work_dir)
+                                .map_err(|err| warn!("failed to sample telemetry: {}", err))
+                                .ok()
+                        }
+                    };
+                    let heartbeat = Heartbeat::new(state, telemetry);
                     let mut retries = 0;
                     debug!("pushing client status");
-                    while let Err(ref err) = connector.status(id, state) {
+                    loop {
+                        let err = match connector.heartbeat(id, &heartbeat) {
+                            Ok(draining) => {
+                                drained.store(draining, Ordering::Relaxed);
+                                break;
+                            }
+                            Err(err) => err,
+                        };
                         if err.is_bad_response() {
                             warn!("failed to push status, retrying registration...");
-                            match connector.register(&hostname) {
+                            match connector.register(&hostname, &labels, &facts) {
                                 Ok(registered) => {
                                     info!("registered");
                                     *host.write().unwrap() = registered;
@@ -182,6 +273,11 @@ impl Client {
             connector,
             executor: None,
             history: None,
+            seen: None,
+            drained,
+            grace,
+            usage_interval,
+            spool,
         })
     }
 
@@ -200,6 +296,8 @@ impl Client {
         for retries in 0..128 {
             match self.connector.current() {
                 Ok(id) => {
+                    self.seen = Some(id);
+                    self.drain();
                     let invocation = { self.host.read().unwrap().current_invocation() };
                     match invocation {
                         Some(oid) if oid != id => {
@@ -210,14 +308,19 @@ impl Client {
                         }
                         _ => {
                             if let Some(ref executor) = self.executor {
-                                if signal::killpg(executor.pid, None).is_err() {
-                                    self.kill()?;
+                                if self.drained.swap(false, Ordering::Relaxed) {
+                                    info!("drained by the server, abandoning invocation...");
+                                    self.kill(true)?;
+                                } else if exited(executor.pid) {
+                                    self.kill(false)?;
                                 }
                             }
                         }
                     }
                     return Ok(());
                 }
+                // There is no current invocation, so there's nothing to run
+                Err(ref err) if err.is_bad_response() => break,
                 _ => {
                     warn!("failed to get current invocation ID, retrying...");
                     let backoff = rand::thread_rng().gen_range(0, 1 << cmp::min(retries, 3));
@@ -225,7 +328,8 @@ impl Client {
                 }
             }
         }
-        self.kill()?;
+        self.seen = None;
+        self.kill(false)?;
         self.set_state(HostState::Idle);
         Ok(())
     }
@@ -248,7 +352,7 @@ impl Client {
                 if !invocation.host_has_logged(self.host.read().unwrap().hostname()) {
                     self.invoke_local(invocation)
                 } else {
-                    self.kill()?;
+                    self.kill(true)?;
                     self.set_state(HostState::Done { id });
                     Ok(None)
                 }
@@ -260,7 +364,9 @@ impl Client {
     fn invoke_local(&mut self, invocation: Invocation) -> Result<Option<Executor>, ClientError> {
         match invocation.split() {
             Some((invocation, descriptor)) => {
-                self.kill()?;
+                // A new invocation only becomes current once the last has finished or been
+                // cancelled, so anything still running was cancelled
+                self.kill(true)?;
                 let mut repo = None;
                 if let Some(ref old) = self.history {
                     if old.invocation.url() == invocation.url() {
@@ -280,6 +386,16 @@ impl Client {
                     Some(repo) => repo,
                     None => self.clone(invocation.url(), invocation.commit())?,
                 };
+                let log = {
+                    let host = self.host.read().unwrap();
+                    format!(
+                        "{}@{}-{}",
+                        host.hostname(),
+                        descriptor.name(),
+                        Utc::now().format("%Y-%m-%dT%H:%M:%S").to_string()
+                    )
+                };
+                let since = time::SystemTime::now();
                 info!("forking child process...");
                 match fork() {
                     Ok(ForkResult::Parent { child, .. }) => {
@@ -288,26 +404,45 @@ impl Client {
                         self.set_state(HostState::Running {
                             id: invocation.id(),
                         });
+                        let streamer = self.stream(&invocation, &descriptor, &log, since);
+                        let recorder = self.usage_interval.map(|interval| {
+                            let path = self
+                                .path
+                                .join(descriptor.log_dir())
+                                .join(ExperimentDescriptor::usage(&log));
+                            Recorder::start(child, path, interval)
+                        });
                         Ok(Some(Executor {
                             pid: child,
+                            start: Utc::now(),
                             descriptor,
                             invocation,
                             repo,
+                            log,
+                            streamer,
+                            recorder,
                         }))
                     }
                     Ok(ForkResult::Child) => {
-                        setpgid(Pid::from_raw(0), Pid::from_raw(0)).unwrap();
+                        // Commands are run in process groups of their own, so the child leads a
+                        // session through which they can all be found and killed
+                        setsid().unwrap();
                         let host = self.host.read().unwrap();
-                        descriptor.execute_for(
+                        let mut reporter = Reporter {
+                            client: self,
+                            invocation: &invocation,
+                            host: host.id(),
+                        };
+                        let report = descriptor.execute_for(
+                            &invocation,
                             host.hostname(),
                             &self.path,
-                            &format!(
-                                "{}@{}-{}",
-                                host.hostname(),
-                                descriptor.name(),
-                                Utc::now().format("%Y-%m-%dT%H:%M:%S").to_string()
-                            ),
+                            &log,
+                            &mut reporter,
                         );
+                        if let Some(report) = report {
+                            self.report(&invocation, host.id(), &report);
+                        }
                         process::exit(0);
                     }
                     Err(err) => Err(ClientError {
@@ -320,16 +455,143 @@ impl Client {
         }
     }
 
-    fn kill(&mut self) -> Result<(), ClientError> {
+    /// Starts streaming the executor's output (and, if the descriptor asks for it, everything else
+    /// written to the log directory) to the server.
+    fn stream(
+        &self,
+        invocation: &InvocationRecord,
+        descriptor: &ExperimentDescriptor,
+        log: &str,
+        since: time::SystemTime,
+    ) -> Option<Streamer> {
+        let sources = if descriptor.tail() {
+            Sources::All(since)
+        } else if descriptor.gen_logs() {
+            let (stdout, stderr) = ExperimentDescriptor::outputs(log);
+            Sources::Files(vec![stdout, stderr])
+        } else {
+            return None;
+        };
+        Some(Streamer::start(
+            Arc::clone(&self.connector),
+            invocation.id(),
+            self.host.read().unwrap().id(),
+            self.path.join(descriptor.log_dir()),
+            sources,
+        ))
+    }
+
+    fn report(&self, invocation: &InvocationRecord, host: HostId, report: &HostReport) {
+        self.deliver("result", || {
+            self.connector.report(invocation.id(), host, report)
+        });
+    }
+
+    fn report_phase(&self, invocation: &InvocationRecord, host: HostId, report: &PhaseReport) {
+        self.deliver(&format!("{} phase", report.phase()), || {
+            self.connector.report_phase(invocation.id(), host, report)
+        });
+    }
+
+    /// Delivers a report to the server. This may be called from the forked child, so retries are
+    /// bounded to avoid leaving it lingering if the server has gone away.
+    fn deliver<F>(&self, what: &str, send: F)
+    where
+        F: Fn() -> Result<(), ResponseError>,
+    {
+        info!("reporting {}...", what);
+        for retries in 0..8 {
+            match send() {
+                Ok(_) => {
+                    info!("reported {}", what);
+                    return;
+                }
+                Err(err) => {
+                    warn!("failed to report {} ({}), retrying...", what, err);
+                    let backoff = rand::thread_rng().gen_range(0, 1 << cmp::min(retries, 3));
+                    thread::sleep(backoff * time::Duration::from_millis(500))
+                }
+            }
+        }
+        error!("giving up on reporting {}", what);
+    }
+
+    /// Runs the teardown phase on behalf of an executor that was killed before it could do so. As
+    /// the client is locked meanwhile, commands without a timeout are given the grace period.
+    fn teardown(&self, executor: &Executor) {
+        let (hostname, id) = {
+            let host = self.host.read().unwrap();
+            (host.hostname().to_string(), host.id())
+        };
+        info!("running teardown...");
+        let report = executor.descriptor.teardown_for(
+            &executor.invocation,
+            &hostname,
+            &self.path,
+            &executor.log,
+            self.grace.as_secs(),
+        );
+        if let Some(report) = report {
+            self.report_phase(&executor.invocation, id, &report);
+        }
+    }
+
+    /// Stops the executor (if there is one) and uploads its logs. Unless `cancelled` (i.e. the
+    /// server asked for the experiment to stop), an experiment still running is reported as
+    /// terminated by whichever signal stopped it.
+    fn kill(&mut self, cancelled: bool) -> Result<(), ClientError> {
         restore_children();
         if self.executor.is_some() {
             self.history = None;
             mem::swap(&mut self.history, &mut self.executor);
+            let streamer = self
+                .history
+                .as_mut()
+                .and_then(|executor| executor.streamer.take());
+            let recorder = self
+                .history
+                .as_mut()
+                .and_then(|executor| executor.recorder.take());
             if let Some(ref executor) = self.history {
-                info!("killing child process...");
-                signal::killpg(executor.pid, signal::SIGTERM).unwrap_or(());
-                signal::killpg(executor.pid, signal::SIGKILL).unwrap_or(());
-                info!("killed child process");
+                // An executor that already exited has run its teardown and reported for itself
+                let running = !exited(executor.pid);
+                info!("terminating child process...");
+                signal_session(executor.pid, signal::SIGTERM);
+                let clean = self.wait_for_exit(executor.pid);
+                if clean {
+                    info!("child process exited cleanly");
+                } else {
+                    warn!(
+                        "child process still running after {}s, killing...",
+                        self.grace.as_secs()
+                    );
+                    signal_session(executor.pid, signal::SIGKILL);
+                    info!("killed child process");
+                }
+                if running {
+                    self.teardown(executor);
+                    let id = self.host.read().unwrap().id();
+                    let termination = if cancelled {
+                        Termination::Cancelled { clean }
+                    } else {
+                        let signal = if clean {
+                            signal::SIGTERM
+                        } else {
+                            signal::SIGKILL
+                        };
+                        Termination::Signalled {
+                            signal: signal as i32,
+                        }
+                    };
+                    let report = HostReport::new(termination, executor.start);
+                    self.report(&executor.invocation, id, &report);
+                }
+                if let Some(recorder) = recorder {
+                    recorder.finish();
+                }
+                if let Some(streamer) = streamer {
+                    streamer.finish();
+                }
                 self.upload(executor)?;
                 self.set_state(HostState::Done {
                     id: executor.invocation.id(),
@@ -339,42 +601,76 @@ impl Client {
         Ok(())
     }
 
+    /// Waits for up to the grace period for every process in the executor's session to exit,
+    /// returning whether they did.
+    fn wait_for_exit(&self, session: Pid) -> bool {
+        let deadline = time::Instant::now() + self.grace;
+        loop {
+            // Reap the executor itself, which would otherwise linger in the session as a zombie
+            waitpid(session, Some(WaitPidFlag::WNOHANG)).ok();
+            if groups_in(session).is_empty() {
+                return true;
+            }
+            if time::Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(EXIT_INTERVAL);
+        }
+    }
+
     fn upload(&self, executor: &Executor) -> Result<(), ClientError> {
-        if let Some(path) = self.compress(executor)? {
+        if self.compress(executor)?.is_some() {
             info!("uploading logs...");
             self.set_state(HostState::Uploading {
                 id: executor.invocation.id(),
             });
-            self.connector
-                .upload(
-                    &path,
-                    executor.invocation.id(),
-                    self.host.read().unwrap().id(),
-                )
-                .map_err(|err| ClientError {
-                    cause: Some(Box::new(err)),
-                    kind: ClientErrorKind::UploadFailed,
-                })?;
-            fs::remove_file(path).unwrap_or(());
+            self.send_spooled(executor.invocation.id())?;
             info!("uploaded logs");
         }
         Ok(())
     }
 
+    /// Uploads the spooled log archive for the given invocation. If the upload fails, the archive
+    /// stays in the spool to be retried by `drain`.
+    fn send_spooled(&self, id: InvocationId) -> Result<(), ClientError> {
+        let host = self.host.read().unwrap().id();
+        self.spool
+            .send(&self.connector, id, host)
+            .map_err(|err| ClientError {
+                cause: Some(err),
+                kind: ClientErrorKind::UploadFailed,
+            })
+    }
+
+    /// Retries uploading any log archives left in the spool by earlier failures.
+    fn drain(&self) {
+        for id in self.spool.pending() {
+            info!("retrying upload of spooled logs for {}...", id);
+            match self.send_spooled(id) {
+                Ok(_) => info!("uploaded spooled logs for {}", id),
+                Err(err) => {
+                    warn!("failed to upload spooled logs for {} ({})", id, err);
+                    break;
+                }
+            }
+        }
+    }
+
     fn compress(&self, executor: &Executor) -> Result<Option<PathBuf>, ClientError> {
         let log_dir = self.path.join(executor.descriptor.log_dir());
         if log_dir.exists() {
             info!("compressing logs...");
-            self.set_state(HostState::Compressing {
-                id: executor.invocation.id(),
-            });
-            let path = self.path.join("archive.tar.gz");
+            let id = executor.invocation.id();
+            self.set_state(HostState::Compressing { id });
+            let path = self.spool.archive(id);
             File::create(&path)
                 .and_then(|tar_gz| {
                     let enc = GzEncoder::new(tar_gz, Compression::default());
                     let mut tar = tar::Builder::new(enc);
-                    tar.append_dir_all(".", log_dir)
+                    tar.append_dir_all(".", log_dir)?;
+                    tar.into_inner()?.finish()
                 })
+                .and_then(|_| self.spool.add(id))
                 .map_err(|err| ClientError {
                     cause: Some(Box::new(err)),
                     kind: ClientErrorKind::CompressionFailed,
@@ -391,6 +687,37 @@ impl Client {
     }
 }
 
+impl<'a> Observer for Reporter<'a> {
+    fn phase_complete(&mut self, report: &PhaseReport) {
+        self.client.report_phase(self.invocation, self.host, report);
+    }
+
+    fn barrier(&mut self, name: &str) {
+        info!("waiting at barrier {}...", name);
+        let mut retries = 0;
+        loop {
+            match self
+                .client
+                .connector
+                .barrier(self.invocation.id(), name, self.host)
+            {
+                Ok(true) => break,
+                Ok(false) => {
+                    retries = 0;
+                    thread::sleep(BARRIER_INTERVAL);
+                }
+                Err(err) => {
+                    warn!("failed to reach barrier {} ({}), retrying...", name, err);
+                    retries = cmp::min(retries + 1, 3);
+                    let backoff = rand::thread_rng().gen_range(0, 1 << retries);
+                    thread::sleep(backoff * time::Duration::from_millis(500))
+                }
+            }
+        }
+        info!("passed barrier {}", name);
+    }
+}
+
 fn main() {
     let matches = App::new("clusterc")
         .version("0.2.0")
@@ -412,6 +739,29 @@ fn main() {
                 .value_name("PORT")
                 .help("the port for the server"),
         )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .value_name("TOKEN")
+                .help("the enrolment token to present to the server (or set CLUSTER_TOKEN)"),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help("a label for experiments to select this host by"),
+        )
+        .arg(
+            Arg::with_name("labels")
+                .long("labels")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("a TOML file of labels for this host, overridden by --label"),
+        )
         .arg(
             Arg::with_name("path")
                 .long("path")
@@ -419,22 +769,84 @@ fn main() {
                 .value_name("PATH")
                 .help("the directory into which experiments will be cloned"),
         )
+        .arg(
+            Arg::with_name("spool")
+                .long("spool")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("the directory in which log archives wait to be uploaded"),
+        )
+        .arg(
+            Arg::with_name("grace")
+                .long("grace")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("how long a cancelled experiment is given to exit before it is killed"),
+        )
+        .arg(
+            Arg::with_name("usage-interval")
+                .long("usage-interval")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("how often to sample the resources used by experiments (0 to disable)"),
+        )
         .get_matches();
     env_logger::init();
     info!("starting client...");
+    let labels = facts::labels(
+        matches.value_of("labels").map(Path::new),
+        &matches
+            .values_of("label")
+            .map(Iterator::collect::<Vec<_>>)
+            .unwrap_or_default(),
+    )
+    .map_err(|err| ClientError {
+        cause: Some(err),
+        kind: ClientErrorKind::InvalidLabels,
+    })
+    .unwrap();
     let client = Arc::new(Mutex::new(
         Client::new(
-            matches.value_of("server").unwrap(),
-            value_t!(matches, "port", u16).unwrap_or(8000),
+            Connector::new(
+                matches.value_of("server").unwrap(),
+                value_t!(matches, "port", u16).unwrap_or(8000),
+                matches
+                    .value_of("token")
+                    .map(str::to_string)
+                    .or_else(|| env::var("CLUSTER_TOKEN").ok()),
+            ),
+            labels,
             matches.value_of("path").unwrap_or("experiment/"),
+            matches.value_of("spool").unwrap_or("spool/"),
+            time::Duration::from_secs(value_t!(matches, "grace", u64).unwrap_or(GRACE_DEFAULT)),
+            match value_t!(matches, "usage-interval", u64).unwrap_or(USAGE_INTERVAL_DEFAULT) {
+                0 => None,
+                interval => Some(time::Duration::from_secs(interval)),
+            },
         )
         .unwrap(),
     ));
     {
         let client = Arc::clone(&client);
         thread::spawn(move || loop {
-            client.lock().unwrap().poll();
-            thread::sleep(time::Duration::from_millis(2000));
+            let (connector, seen, running) = {
+                let mut client = client.lock().unwrap();
+                client.poll();
+                (
+                    Arc::clone(&client.connector),
+                    client.seen,
+                    client.executor.is_some(),
+                )
+            };
+            // Wait without holding the lock, so that the client can still be killed meanwhile
+            let timeout = if running { POLL_INTERVAL } else { LONG_POLL };
+            if let Err(err) = connector.wait(seen, timeout) {
+                debug!(
+                    "couldn't wait for the current invocation ({}), polling",
+                    err
+                );
+                thread::sleep(POLL_INTERVAL);
+            }
         });
     }
     let term = Arc::new(AtomicBool::new(false));
@@ -445,7 +857,7 @@ fn main() {
     while !term.load(Ordering::Relaxed) {
         if term.swap(false, Ordering::Relaxed) {
             info!("exiting...");
-            match client.lock().unwrap().kill() {
+            match client.lock().unwrap().kill(false) {
                 Ok(_) => process::exit(0),
                 _ => process::exit(1),
             }
@@ -453,6 +865,18 @@ fn main() {
     }
 }
 
+/// Whether the executor with the given PID has exited, reaping it if it lingers as a zombie (as
+/// when it exited before children were set to be reaped automatically, or since they were
+/// restored).
+fn exited(pid: Pid) -> bool {
+    match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => false,
+        Ok(_) => true,
+        // Already reaped (or never ours to wait on), so see whether its group is still around
+        Err(_) => signal::killpg(pid, None).is_err(),
+    }
+}
+
 fn ignore_children() {
     unsafe {
         signal::sigaction(
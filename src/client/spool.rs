@@ -0,0 +1,165 @@
+use crate::api::Connector;
+
+use cluster::host::HostId;
+use cluster::invocation::InvocationId;
+use cluster::upload::UploadId;
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::{cmp, thread, time};
+
+/// The most sent to the server in a single request, in bytes.
+const CHUNK: usize = 1 << 20;
+/// How many times in a row a chunk may fail to send before the upload is given up on (until the
+/// spool is next drained).
+const RETRIES: usize = 8;
+
+/// A directory of log archives waiting to be uploaded. Archives stay in the spool until the
+/// server has received and verified them, surviving failed uploads and restarts of the client.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+/// What is known about a spooled archive, kept alongside it.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    invocation: InvocationId,
+    /// The chunked upload in progress, if one has been started.
+    session: Option<UploadId>,
+}
+
+impl Spool {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Spool> {
+        fs::create_dir_all(&dir)?;
+        Ok(Spool {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Where the log archive for the given invocation should be written before it is added.
+    pub fn archive(&self, id: InvocationId) -> PathBuf {
+        self.dir.join(format!("{}.tar.gz", id))
+    }
+
+    /// Adds the archive written for the given invocation to the spool.
+    pub fn add(&self, id: InvocationId) -> io::Result<()> {
+        self.write(&Entry {
+            invocation: id,
+            session: None,
+        })
+    }
+
+    /// The invocations with archives still waiting to be uploaded.
+    pub fn pending(&self) -> Vec<InvocationId> {
+        fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+                    .filter_map(|path| self.read(&path).ok())
+                    .map(|entry| entry.invocation)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Uploads the spooled archive for the given invocation, resuming any upload already in
+    /// progress, and removes it from the spool once the server has it.
+    pub fn send(
+        &self,
+        connector: &Connector,
+        id: InvocationId,
+        host: HostId,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut entry = self.read(&self.entry(id))?;
+        let path = self.archive(id);
+        let size = fs::metadata(&path)?.len();
+        let resumed = entry
+            .session
+            .and_then(|session| connector.upload_status(session).ok());
+        let mut status = match resumed {
+            Some(status) => {
+                info!("resuming upload from byte {} of {}", status.offset(), size);
+                status
+            }
+            None => {
+                let checksum = sha256(&path)?;
+                let status = connector.begin_upload(id, host, size, &checksum)?;
+                entry.session = Some(status.id());
+                self.write(&entry)?;
+                status
+            }
+        };
+        let mut file = File::open(&path)?;
+        let mut buf = vec![0; CHUNK];
+        let mut retries = 0;
+        while !status.complete() {
+            file.seek(SeekFrom::Start(status.offset()))?;
+            let read = read_chunk(&mut file, &mut buf)?;
+            match connector.upload_chunk(status.id(), status.offset(), buf[..read].to_vec()) {
+                Ok(next) => {
+                    status = next;
+                    retries = 0;
+                }
+                Err(err) if retries < RETRIES => {
+                    warn!("failed to upload chunk ({}), retrying...", err);
+                    retries += 1;
+                    let backoff = rand::thread_rng().gen_range(0, 1 << cmp::min(retries, 3));
+                    thread::sleep(backoff * time::Duration::from_millis(500));
+                    // The server may have received the chunk even if the response was lost
+                    if let Ok(current) = connector.upload_status(status.id()) {
+                        status = current;
+                    }
+                }
+                Err(err) => return Err(Box::new(err)),
+            }
+        }
+        fs::remove_file(&path).unwrap_or(());
+        fs::remove_file(self.entry(id)).unwrap_or(());
+        Ok(())
+    }
+
+    fn entry(&self, id: InvocationId) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Entry> {
+        serde_json::from_reader(File::open(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn write(&self, entry: &Entry) -> io::Result<()> {
+        let contents = serde_json::to_vec(entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(self.entry(entry.invocation), contents)
+    }
+}
+
+/// Fills as much of `buf` as the rest of the file allows.
+fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// The hex-encoded SHA-256 digest of the file at the given path.
+fn sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
@@ -0,0 +1,147 @@
+use crate::api::Connector;
+
+use cluster::host::HostId;
+use cluster::invocation::InvocationId;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+/// How often files are checked for newly written data.
+const STREAM_INTERVAL: time::Duration = time::Duration::from_millis(1000);
+/// The most sent to the server in a single request, in bytes.
+const CHUNK: usize = 256 * 1024;
+
+/// The files to be streamed from the log directory.
+pub enum Sources {
+    /// Only the given files.
+    Files(Vec<PathBuf>),
+    /// Every file modified since the invocation started.
+    All(time::SystemTime),
+}
+
+/// Streams files from the log directory to the server as they are written, from a background
+/// thread, until finished.
+pub struct Streamer {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+/// The state of the background thread.
+struct Tail {
+    connector: Arc<Connector>,
+    id: InvocationId,
+    host: HostId,
+    dir: PathBuf,
+    sources: Sources,
+    /// How much of each file the server has, keyed by path relative to the log directory.
+    offsets: HashMap<PathBuf, u64>,
+}
+
+impl Streamer {
+    pub fn start<P: AsRef<Path>>(
+        connector: Arc<Connector>,
+        id: InvocationId,
+        host: HostId,
+        dir: P,
+        sources: Sources,
+    ) -> Streamer {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut tail = Tail {
+            connector,
+            id,
+            host,
+            dir: dir.as_ref().to_path_buf(),
+            sources,
+            offsets: HashMap::new(),
+        };
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || loop {
+                // Checked before sending so that one last pass is made after being stopped
+                let stopping = stop.load(Ordering::Relaxed);
+                tail.send();
+                if stopping {
+                    break;
+                }
+                thread::sleep(STREAM_INTERVAL);
+            })
+        };
+        Streamer { stop, handle }
+    }
+
+    /// Sends anything written since the last pass, then stops streaming.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().unwrap_or(());
+    }
+}
+
+impl Tail {
+    fn send(&mut self) {
+        for name in self.names() {
+            if let Err(err) = self.send_file(&name) {
+                debug!("failed to stream {} ({})", name.display(), err);
+            }
+        }
+    }
+
+    /// The paths, relative to the log directory, of every file to be streamed.
+    fn names(&self) -> Vec<PathBuf> {
+        match self.sources {
+            Sources::Files(ref names) => names.clone(),
+            Sources::All(since) => {
+                let mut names = vec![];
+                walk(&self.dir, &self.dir, since, &mut names);
+                names
+            }
+        }
+    }
+
+    fn send_file(&mut self, name: &Path) -> io::Result<()> {
+        let mut file = match File::open(self.dir.join(name)) {
+            Ok(file) => file,
+            // The file may just not have been written to yet
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut buf = vec![0; CHUNK];
+        loop {
+            let offset = *self.offsets.get(name).unwrap_or(&0);
+            file.seek(SeekFrom::Start(offset))?;
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(());
+            }
+            let length = self
+                .connector
+                .stream(self.id, self.host, name, offset, buf[..read].to_vec())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{}", err)))?;
+            self.offsets.insert(name.to_path_buf(), length);
+        }
+    }
+}
+
+fn walk(root: &Path, dir: &Path, since: time::SystemTime, names: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        _ => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            _ => continue,
+        };
+        if metadata.is_dir() {
+            walk(root, &entry.path(), since, names);
+        } else if metadata.modified().map(|at| at >= since).unwrap_or(false) {
+            if let Ok(name) = entry.path().strip_prefix(root) {
+                names.push(name.to_path_buf());
+            }
+        }
+    }
+}
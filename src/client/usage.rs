@@ -0,0 +1,70 @@
+use chrono::Utc;
+
+use cluster::process::Usage;
+
+use nix::unistd::Pid;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+/// The columns of the recorded time series.
+const HEADER: &str = "time,processes,cpu_seconds,rss_bytes,read_bytes,written_bytes";
+
+/// Records the resources used by every process in an executor's session to a CSV file, from a
+/// background thread, until finished.
+pub struct Recorder {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Recorder {
+    pub fn start<P: AsRef<Path>>(session: Pid, path: P, interval: time::Duration) -> Recorder {
+        let stop = Arc::new(AtomicBool::new(false));
+        let path = path.as_ref().to_path_buf();
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let Err(err) = record(session, &path) {
+                        warn!("failed to record resource usage: {}", err);
+                    }
+                    thread::sleep(interval);
+                }
+            })
+        };
+        Recorder { stop, handle }
+    }
+
+    /// Stops recording, once the current sample has been written.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().unwrap_or(());
+    }
+}
+
+/// Appends a sample to the file, which is reopened every time in case the experiment has moved or
+/// removed it (or the log directory it lives in).
+fn record(session: Pid, path: &Path) -> io::Result<()> {
+    let usage = Usage::of_session(session);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", HEADER)?;
+    }
+    writeln!(
+        file,
+        "{},{},{:.2},{},{},{}",
+        Utc::now().to_rfc3339(),
+        usage.processes,
+        usage.cpu,
+        usage.rss,
+        usage.read,
+        usage.written
+    )
+}
@@ -1,41 +1,152 @@
+use chrono::Utc;
+
+use crate::invocation::InvocationRecord;
+use crate::process;
+use crate::report::{HostReport, PhaseReport, Termination};
+
+use nix::unistd::{setpgid, Pid};
+
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::Read;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::str;
+use std::{str, time};
 
 /// The name of the experiment manifest file.
 const MANIFEST: &str = "deployment.toml";
+/// The entry in `hosts` used for any host not otherwise matched.
+const DEFAULT_HOST: &str = "default";
 /// By default, will log files be generated by clients from their standard output and standard
 /// error.
 const GEN_LOGS_DEFAULT: bool = false;
+/// By default, how many seconds a timed out (or cancelled) command is given to exit after SIGTERM
+/// before it is sent SIGKILL.
+pub const GRACE_DEFAULT: u64 = 10;
 lazy_static! {
     /// In the event that logs are to be generated and no directory has been specified, this is
     /// where logs will be written to.
     static ref LOG_DIR_DEFAULT: PathBuf = PathBuf::from("logs/");
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ExperimentDescriptor {
     name: String,
-    command: Option<String>,
-    args: Option<Vec<String>>,
+    #[serde(flatten)]
+    phases: Phases,
+    /// Environment variables set for every command, on every host.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Parameters to sweep over. The experiment is run once for every combination of values,
+    /// with `${name}` in arguments and environment variables replaced by the value of `name`.
+    #[serde(default)]
+    matrix: BTreeMap<String, Vec<toml::Value>>,
+    /// Hosts keyed by exact hostname, by a hostname pattern (in which `*` matches any run of
+    /// characters and `?` any single character), or by `default`.
+    #[serde(default)]
     hosts: HashMap<String, HostDescriptor>,
+    /// Roles to be filled by whichever hosts are available when the invocation is run.
+    #[serde(default)]
+    roles: BTreeMap<String, RoleDescriptor>,
+    /// How many seconds any one command may run for before it is terminated, unless overridden by
+    /// a host or phase.
+    timeout: Option<u64>,
+    /// How many seconds a timed out command is given to exit after SIGTERM before it is sent
+    /// SIGKILL.
+    #[serde(default = "grace_default")]
+    grace: u64,
     #[serde(default = "gen_logs_default")]
     gen_logs: bool,
+    /// Whether every file written to the log directory is streamed to the server as it is
+    /// written, rather than only the generated standard output and standard error.
+    #[serde(default)]
+    tail: bool,
     #[serde(default = "log_dir_default")]
     log_dir: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct HostDescriptor {
+    #[serde(flatten)]
+    phases: Phases,
+    /// Environment variables set for every command on this host, overriding those set for the
+    /// experiment as a whole.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// How many seconds any one command run on this host may run for, overriding the timeout set
+    /// for the experiment as a whole.
+    timeout: Option<u64>,
+    /// What a host must offer to be matched by a pattern, the default entry or a role, keyed by
+    /// label or fact. Values are compared exactly (or as patterns), unless prefixed by one of
+    /// `>=`, `<=`, `>`, `<`, `=` or `!=`, in which case they are compared as numbers.
+    #[serde(default)]
+    requires: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RoleDescriptor {
+    /// How many hosts should be assigned the role.
+    count: usize,
+    #[serde(flatten)]
+    host: HostDescriptor,
+}
+
+/// The commands to run in each phase, either for every host or for one host in particular. For
+/// each phase, the experiment-wide command is run before the host-specific one.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Phases {
+    /// Shorthand for a run phase consisting of a single command (predating named phases).
     command: Option<String>,
     args: Option<Vec<String>>,
+    setup: Option<PhaseDescriptor>,
+    run: Option<PhaseDescriptor>,
+    teardown: Option<PhaseDescriptor>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct PhaseDescriptor {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Which hosts must be ready before the phase may start on any of them.
+    wait_for: Option<WaitFor>,
+    /// How many seconds the phase's command may run for, overriding any other timeout.
+    timeout: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WaitFor {
+    /// Every participating host must arrive at the phase before any of them start it.
+    #[serde(rename = "all")]
+    All,
+}
+
+/// Hooks through which an executing experiment interacts with the rest of the cluster.
+pub trait Observer {
+    /// Called as each phase finishes running.
+    fn phase_complete(&mut self, report: &PhaseReport);
+    /// Blocks until every participating host has arrived at the named barrier.
+    fn barrier(&mut self, name: &str);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Phase {
+    /// Prepares the host for the experiment. If setup fails, the run phase is skipped.
+    #[serde(rename = "setup")]
+    Setup,
+    /// The experiment itself.
+    #[serde(rename = "run")]
+    Run,
+    /// Cleans up after the experiment. Teardown always runs, even if an earlier phase failed or
+    /// the invocation was cancelled.
+    #[serde(rename = "teardown")]
+    Teardown,
 }
 
 #[derive(Debug)]
@@ -96,44 +207,503 @@ impl ExperimentDescriptor {
         &self.log_dir
     }
 
-    pub fn execute_for<P: AsRef<Path>, Q: AsRef<Path>>(&self, hostname: &str, work_dir: P, log: Q) {
-        if let Some(host) = self.hosts.get(hostname) {
-            let mut options = OpenOptions::new();
-            options.append(true).create(true);
-            let log_dir = work_dir.as_ref().join(self.log_dir());
-            fs::create_dir_all(&log_dir).unwrap_or(());
-            let stdout = log_dir.join(&log).with_extension("stdout");
-            let stderr = log_dir.join(&log).with_extension("stderr");
-            if let Some(ref command) = self.command {
-                let mut command = Command::new(command);
-                command.current_dir(&work_dir);
-                if let Some(ref args) = self.args {
-                    for arg in args.iter() {
-                        command.arg(arg);
+    pub fn gen_logs(&self) -> bool {
+        self.gen_logs
+    }
+
+    pub fn tail(&self) -> bool {
+        self.tail
+    }
+
+    /// The files, relative to the log directory, to which the standard output and standard error
+    /// of commands are written when logs are generated.
+    pub fn outputs<P: AsRef<Path>>(log: P) -> (PathBuf, PathBuf) {
+        (
+            log.as_ref().with_extension("stdout"),
+            log.as_ref().with_extension("stderr"),
+        )
+    }
+
+    /// The file, relative to the log directory, to which the resources used by the experiment are
+    /// recorded.
+    pub fn usage<P: AsRef<Path>>(log: P) -> PathBuf {
+        log.as_ref().with_extension("usage.csv")
+    }
+
+    /// The sorted hostnames of every host named explicitly (rather than by pattern or role).
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames = self
+            .hosts
+            .keys()
+            .filter(|key| *key != DEFAULT_HOST && !is_pattern(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        hostnames.sort();
+        hostnames
+    }
+
+    /// Decides which hosts take part in an invocation, given every host currently available (keyed
+    /// by hostname, along with its attributes) and the hostnames of those that are cordoned,
+    /// returning the sorted participants and the roles assigned to them. Hosts named explicitly
+    /// always take part, whether or not they are available. Roles are then filled from the
+    /// remaining available hosts that meet their requirements (in hostname order), and whatever is
+    /// left takes part only if matched by a pattern or the default entry whose requirements it
+    /// meets. Fails, saying why, if a host named explicitly is cordoned or there aren't enough
+    /// hosts to fill every role.
+    pub fn assign(
+        &self,
+        available: &BTreeMap<String, BTreeMap<String, String>>,
+        cordoned: &BTreeSet<String>,
+    ) -> Result<(Vec<String>, BTreeMap<String, String>), String> {
+        let mut participants = self.hostnames().into_iter().collect::<BTreeSet<_>>();
+        if let Some(hostname) = participants.intersection(cordoned).next() {
+            return Err(format!("host {} is cordoned", hostname));
+        }
+        let mut remaining = available
+            .iter()
+            .filter(|(hostname, _)| {
+                !participants.contains(*hostname) && !cordoned.contains(*hostname)
+            })
+            .collect::<Vec<_>>();
+        let mut roles = BTreeMap::new();
+        for (role, descriptor) in self.roles.iter() {
+            for assigned in 0..descriptor.count {
+                let found = remaining
+                    .iter()
+                    .position(|(_, attributes)| descriptor.host.satisfied_by(attributes));
+                match found {
+                    Some(index) => {
+                        let (hostname, _) = remaining.remove(index);
+                        participants.insert(hostname.clone());
+                        roles.insert(hostname.clone(), role.clone());
+                    }
+                    None => {
+                        return Err(format!(
+                            "only {} of {} hosts available for role {}",
+                            assigned, descriptor.count, role
+                        ))
                     }
                 }
-                if self.gen_logs {
-                    command.stdout(Stdio::from(options.open(&stdout).unwrap()));
-                    command.stderr(Stdio::from(options.open(&stderr).unwrap()));
+            }
+        }
+        for (hostname, attributes) in remaining {
+            match self.matching(hostname) {
+                Some(host) if host.satisfied_by(attributes) => {
+                    participants.insert(hostname.clone());
                 }
-                command.status().unwrap();
+                _ => (),
             }
-            if let Some(ref command) = host.command {
-                let mut command = Command::new(command);
-                command.current_dir(&work_dir);
-                if let Some(ref args) = host.args {
-                    for arg in args.iter() {
-                        command.arg(arg);
-                    }
+        }
+        Ok((participants.into_iter().collect(), roles))
+    }
+
+    /// The descriptor for the given host, either from its role or by its hostname.
+    fn host_for(&self, hostname: &str, role: Option<&str>) -> Option<&HostDescriptor> {
+        match role {
+            Some(role) => self.roles.get(role).map(|role| &role.host),
+            None => self.matching(hostname),
+        }
+    }
+
+    /// The descriptor for the given hostname, preferring an exact match over a pattern (patterns
+    /// are tried in lexicographic order) and a pattern over the default entry.
+    fn matching(&self, hostname: &str) -> Option<&HostDescriptor> {
+        if let Some(host) = self.hosts.get(hostname) {
+            return Some(host);
+        }
+        let mut patterns = self
+            .hosts
+            .keys()
+            .filter(|key| is_pattern(key))
+            .collect::<Vec<_>>();
+        patterns.sort();
+        patterns
+            .into_iter()
+            .find(|pattern| glob_match(pattern, hostname))
+            .and_then(|pattern| self.hosts.get(pattern))
+            .or_else(|| self.hosts.get(DEFAULT_HOST))
+    }
+
+    /// Every combination of values in the experiment's matrix, or nothing if it has no matrix.
+    pub fn combinations(&self) -> Vec<BTreeMap<String, String>> {
+        if self.matrix.is_empty() {
+            return vec![];
+        }
+        let mut combinations = vec![BTreeMap::new()];
+        for (name, values) in self.matrix.iter() {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        let value = match value {
+                            toml::Value::String(value) => value.clone(),
+                            value => format!("{}", value),
+                        };
+                        combination.insert(name.clone(), value);
+                        combination
+                    })
+                })
+                .collect();
+        }
+        combinations
+    }
+
+    /// Runs every phase of the experiment for the given host, passing each phase's report to
+    /// `observer` as it completes and waiting at any barriers the descriptor requires. Returns a
+    /// report of how the experiment as a whole terminated, reflecting the first command to fail
+    /// (or the last to run if none fail), or `None` if the host takes no part in the experiment.
+    pub fn execute_for<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+        work_dir: P,
+        log: Q,
+        observer: &mut dyn Observer,
+    ) -> Option<HostReport> {
+        self.participant(invocation, hostname)?;
+        let start = Utc::now();
+        let mut termination = Termination::Exited { code: 0 };
+        for phase in Phase::ALL.iter() {
+            // Hosts wait at barriers even for phases they will skip, so as not to hold up others
+            if self.waits_for(invocation, *phase) {
+                observer.barrier(&format!("{}", phase));
+            }
+            if !termination.success() && *phase != Phase::Teardown {
+                continue;
+            }
+            let report = self.execute_phase_for(invocation, hostname, *phase, &work_dir, &log);
+            if let Some(report) = report {
+                observer.phase_complete(&report);
+                if termination.success() {
+                    termination = report.termination().clone();
+                }
+            }
+        }
+        Some(HostReport::new(termination, start))
+    }
+
+    /// Runs a single phase of the experiment for the given host, returning `None` if the phase has
+    /// nothing to run. Every command in the phase is run, even if an earlier one fails.
+    pub fn execute_phase_for<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+        phase: Phase,
+        work_dir: P,
+        log: Q,
+    ) -> Option<PhaseReport> {
+        let commands = self.commands_for(invocation, hostname, phase)?;
+        if commands.is_empty() {
+            return None;
+        }
+        let start = Utc::now();
+        let log_dir = work_dir.as_ref().join(self.log_dir());
+        fs::create_dir_all(&log_dir).unwrap_or(());
+        let (stdout, stderr) = ExperimentDescriptor::outputs(log_dir.join(&log));
+        let env = self.environment(invocation, hostname);
+        let mut termination = Termination::Exited { code: 0 };
+        for (command, args, timeout) in commands {
+            let args = args
+                .iter()
+                .map(|arg| substitute(arg, invocation.parameters()))
+                .collect::<Vec<_>>();
+            let result = self.run(command, &args, &env, timeout, &work_dir, &stdout, &stderr);
+            if termination.success() {
+                termination = result;
+            }
+        }
+        Some(PhaseReport::new(phase, termination, start))
+    }
+
+    /// The commands the given host runs in a phase, experiment-wide ones first, along with their
+    /// arguments and timeouts. A phase's own timeout takes precedence over the host's, which takes
+    /// precedence over the experiment's. Returns `None` if the host takes no part in the
+    /// invocation.
+    fn commands_for(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+        phase: Phase,
+    ) -> Option<Vec<(&str, &[String], Option<u64>)>> {
+        let host = self.participant(invocation, hostname)?;
+        let commands = self
+            .phases
+            .commands(phase)
+            .into_iter()
+            .chain(host.phases.commands(phase))
+            .map(|(command, args, timeout)| {
+                (command, args, timeout.or(host.timeout).or(self.timeout))
+            })
+            .collect();
+        Some(commands)
+    }
+
+    /// Runs the teardown phase for the given host on its own, as when the rest of the experiment
+    /// was killed. Commands without a timeout of their own are given `timeout` seconds, so that a
+    /// teardown that hangs can't hold up the host indefinitely.
+    pub fn teardown_for<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+        work_dir: P,
+        log: Q,
+        timeout: u64,
+    ) -> Option<PhaseReport> {
+        let mut descriptor = self.clone();
+        descriptor.timeout = descriptor.timeout.or(Some(timeout));
+        descriptor.execute_phase_for(invocation, hostname, Phase::Teardown, work_dir, log)
+    }
+
+    /// The environment variables to set for every command run on the given host. Along with those
+    /// from the descriptor, variables are injected describing the invocation and the host's place
+    /// in it, so that experiments can discover their role and peers.
+    pub fn environment(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+    ) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        if let Some(host) = self.participant(invocation, hostname) {
+            env.extend(host.env.clone());
+        }
+        for value in env.values_mut() {
+            *value = substitute(value, invocation.parameters());
+        }
+        let hostnames = invocation.participants();
+        env.insert(
+            "CLUSTER_INVOCATION_ID".to_string(),
+            format!("{}", invocation.id()),
+        );
+        env.insert("CLUSTER_HOSTNAME".to_string(), hostname.to_string());
+        env.insert(
+            "CLUSTER_COMMIT".to_string(),
+            invocation.commit().to_string(),
+        );
+        if let Some(index) = hostnames.iter().position(|other| other == hostname) {
+            env.insert("CLUSTER_HOST_INDEX".to_string(), format!("{}", index));
+        }
+        env.insert("CLUSTER_HOSTS".to_string(), hostnames.join(","));
+        if let Some(role) = invocation.role(hostname) {
+            env.insert("CLUSTER_ROLE".to_string(), role.to_string());
+        }
+        env
+    }
+
+    /// Whether there is a barrier before the given phase, which is the case if the experiment or
+    /// any participating host asks for one. Every participant must agree on this, as the barrier
+    /// is only released once they have all arrived.
+    fn waits_for(&self, invocation: &InvocationRecord, phase: Phase) -> bool {
+        self.phases.wait_for(phase).is_some()
+            || invocation.participants().iter().any(|hostname| {
+                self.host_for(hostname, invocation.role(hostname))
+                    .and_then(|host| host.phases.wait_for(phase))
+                    .is_some()
+            })
+    }
+
+    /// The descriptor for the given host, or `None` if it takes no part in the invocation.
+    fn participant(
+        &self,
+        invocation: &InvocationRecord,
+        hostname: &str,
+    ) -> Option<&HostDescriptor> {
+        if !invocation
+            .participants()
+            .iter()
+            .any(|other| other == hostname)
+        {
+            return None;
+        }
+        self.host_for(hostname, invocation.role(hostname))
+    }
+
+    fn run<P: AsRef<Path>>(
+        &self,
+        command: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+        timeout: Option<u64>,
+        work_dir: P,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Termination {
+        let mut command = Command::new(command);
+        command.current_dir(&work_dir);
+        for arg in args.iter() {
+            command.arg(arg);
+        }
+        command.envs(env);
+        // Each command gets a process group of its own, so that it (and anything it spawns) can
+        // be terminated on timeout without taking down the client along with it
+        unsafe {
+            command.pre_exec(|| {
+                setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                    .map(|_| ())
+                    .map_err(|_| io::Error::last_os_error())
+            });
+        }
+        if self.gen_logs {
+            let mut options = OpenOptions::new();
+            options.append(true).create(true);
+            match (options.open(stdout), options.open(stderr)) {
+                (Ok(stdout), Ok(stderr)) => {
+                    command.stdout(Stdio::from(stdout));
+                    command.stderr(Stdio::from(stderr));
                 }
-                if self.gen_logs {
-                    command.stdout(Stdio::from(options.open(&stdout).unwrap()));
-                    command.stderr(Stdio::from(options.open(&stderr).unwrap()));
+                (Err(err), _) | (_, Err(err)) => {
+                    return Termination::Failed {
+                        msg: format!("{}", err),
+                    }
                 }
-                command.status().unwrap();
             }
         }
+        let result = command.spawn().and_then(|mut child| {
+            process::wait_timeout(
+                &mut child,
+                timeout.map(time::Duration::from_secs),
+                time::Duration::from_secs(self.grace),
+            )
+        });
+        match (result, timeout) {
+            (Ok((_, true)), Some(timeout)) => Termination::TimedOut { timeout },
+            (Ok((status, _)), _) => status.into(),
+            (Err(err), _) => Termination::Failed {
+                msg: format!("{}", err),
+            },
+        }
+    }
+}
+
+impl HostDescriptor {
+    /// Whether a host with the given attributes meets every requirement. Hosts lacking an
+    /// attribute that is required don't.
+    fn satisfied_by(&self, attributes: &BTreeMap<String, String>) -> bool {
+        self.requires.iter().all(|(name, requirement)| {
+            attributes
+                .get(name)
+                .map(|value| satisfies(value, requirement))
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl Phases {
+    fn descriptor(&self, phase: Phase) -> &Option<PhaseDescriptor> {
+        match phase {
+            Phase::Setup => &self.setup,
+            Phase::Run => &self.run,
+            Phase::Teardown => &self.teardown,
+        }
+    }
+
+    fn wait_for(&self, phase: Phase) -> Option<WaitFor> {
+        self.descriptor(phase)
+            .as_ref()
+            .and_then(|descriptor| descriptor.wait_for)
+    }
+
+    /// The command (if any) to run in the given phase, along with its arguments and the timeout
+    /// set for the phase.
+    fn commands(&self, phase: Phase) -> Option<(&str, &[String], Option<u64>)> {
+        match (self.descriptor(phase), phase, &self.command) {
+            (Some(descriptor), _, _) => Some((
+                descriptor.command.as_str(),
+                descriptor.args.as_slice(),
+                descriptor.timeout,
+            )),
+            (None, Phase::Run, Some(command)) => Some((
+                command.as_str(),
+                self.args.as_ref().map(Vec::as_slice).unwrap_or(&[]),
+                None,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Phase {
+    /// Every phase, in the order in which they are run.
+    pub const ALL: [Phase; 3] = [Phase::Setup, Phase::Run, Phase::Teardown];
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Setup => write!(f, "setup"),
+            Phase::Run => write!(f, "run"),
+            Phase::Teardown => write!(f, "teardown"),
+        }
+    }
+}
+
+/// Whether a host's attribute meets a requirement, which is either a value (or pattern) to match
+/// exactly or a value prefixed by a comparison. Comparisons are numeric, except that `=` and `!=`
+/// fall back to comparing text.
+fn satisfies(value: &str, requirement: &toml::Value) -> bool {
+    let requirement = match requirement {
+        toml::Value::String(requirement) => requirement.trim().to_string(),
+        requirement => format!("{}", requirement),
+    };
+    // Longer operators first, so that `>=` isn't taken for `>`
+    for operator in &[">=", "<=", "!=", ">", "<", "="] {
+        if requirement.starts_with(operator) {
+            let expected = requirement[operator.len()..].trim();
+            let ordering = match (value.trim().parse::<f64>(), expected.parse::<f64>()) {
+                (Ok(actual), Ok(expected)) => actual.partial_cmp(&expected),
+                _ => None,
+            };
+            return match (*operator, ordering) {
+                (">=", Some(ordering)) => ordering != Ordering::Less,
+                ("<=", Some(ordering)) => ordering != Ordering::Greater,
+                (">", Some(ordering)) => ordering == Ordering::Greater,
+                ("<", Some(ordering)) => ordering == Ordering::Less,
+                ("=", Some(ordering)) => ordering == Ordering::Equal,
+                ("!=", Some(ordering)) => ordering != Ordering::Equal,
+                ("=", None) => value.trim() == expected,
+                ("!=", None) => value.trim() != expected,
+                _ => false,
+            };
+        }
+    }
+    glob_match(&requirement, value)
+}
+
+/// Replaces every occurrence of `${name}` in `s` with the value of the parameter `name`.
+fn substitute(s: &str, parameters: &BTreeMap<String, String>) -> String {
+    parameters.iter().fold(s.to_string(), |s, (name, value)| {
+        s.replace(&format!("${{{}}}", name), value)
+    })
+}
+
+fn is_pattern(key: &str) -> bool {
+    key.contains(|c: char| c == '*' || c == '?')
+}
+
+/// Matches `text` against a pattern in which `*` matches any run of characters and `?` matches any
+/// single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume from if the most recent `*` needs to match more characters
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
     }
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 /// These are work-around helper functions to avoid the fact that serde does not currently support
@@ -143,7 +713,251 @@ const fn gen_logs_default() -> bool {
     GEN_LOGS_DEFAULT
 }
 
+#[inline]
+const fn grace_default() -> u64 {
+    GRACE_DEFAULT
+}
+
 #[inline]
 fn log_dir_default() -> PathBuf {
     LOG_DIR_DEFAULT.to_path_buf()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn requirement(s: &str) -> toml::Value {
+        toml::Value::String(s.to_string())
+    }
+
+    #[test]
+    fn satisfies_numeric_comparisons() {
+        assert!(satisfies("8", &requirement(">=8")));
+        assert!(!satisfies("8", &requirement(">8")));
+        assert!(satisfies("9", &requirement(">8")));
+        assert!(satisfies("8", &requirement("<=8")));
+        assert!(!satisfies("8", &requirement("<8")));
+        assert!(satisfies("2.5", &requirement("< 3")));
+        assert!(satisfies("16", &requirement("=16.0")));
+        assert!(!satisfies("16", &requirement("!=16")));
+        // Comparisons other than equality never hold for values that aren't numbers
+        assert!(!satisfies("many", &requirement(">=8")));
+        assert!(!satisfies("8", &requirement(">=many")));
+    }
+
+    #[test]
+    fn satisfies_text_equality() {
+        assert!(satisfies("x86_64", &requirement("=x86_64")));
+        assert!(!satisfies("aarch64", &requirement("=x86_64")));
+        assert!(satisfies("aarch64", &requirement("!=x86_64")));
+        assert!(!satisfies("x86_64", &requirement("!= x86_64")));
+    }
+
+    #[test]
+    fn satisfies_patterns() {
+        assert!(satisfies("x86_64", &requirement("x86_64")));
+        assert!(satisfies("x86_64", &requirement("x86*")));
+        assert!(satisfies("rack-3", &requirement("rack-?")));
+        assert!(!satisfies("rack-13", &requirement("rack-?")));
+        assert!(!satisfies("arm", &requirement("x86*")));
+    }
+
+    #[test]
+    fn glob_match_literals() {
+        assert!(glob_match("", ""));
+        assert!(glob_match("node-1", "node-1"));
+        assert!(!glob_match("node-1", "node-10"));
+        assert!(!glob_match("node-10", "node-1"));
+        assert!(!glob_match("", "node"));
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("node-*", "node-"));
+        assert!(glob_match("*-1", "node-1"));
+        assert!(glob_match("n*e-?", "node-1"));
+        assert!(glob_match("**", "node"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("node-??", "node-1"));
+        assert!(!glob_match("*-2", "node-1"));
+    }
+
+    #[test]
+    fn glob_match_backtracks() {
+        // The first candidate for each `*` is not always the right one
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("a*b*c", "abbbc"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(glob_match("rack-*-node-*", "rack-1-node-node-2"));
+        assert!(!glob_match("*a*b", "xaxxa"));
+        assert!(!glob_match("a*b", "ab-"));
+    }
+
+    #[test]
+    fn satisfies_non_string_values() {
+        assert!(satisfies("4", &toml::Value::Integer(4)));
+        assert!(!satisfies("8", &toml::Value::Integer(4)));
+        assert!(satisfies("true", &toml::Value::Boolean(true)));
+    }
+
+    #[test]
+    fn satisfied_by_requires_every_attribute() {
+        let host = toml::from_str::<HostDescriptor>(
+            r#"
+            [requires]
+            cores = ">=4"
+            arch = "x86*"
+            "#,
+        )
+        .unwrap();
+        assert!(host.satisfied_by(&attributes(&[("cores", "8"), ("arch", "x86_64")])));
+        assert!(!host.satisfied_by(&attributes(&[("cores", "2"), ("arch", "x86_64")])));
+        // Missing attributes don't satisfy a requirement
+        assert!(!host.satisfied_by(&attributes(&[("cores", "8")])));
+        assert!(HostDescriptor::default().satisfied_by(&attributes(&[])));
+    }
+
+    fn hostnames(hostnames: &[&str]) -> BTreeSet<String> {
+        hostnames
+            .iter()
+            .map(|hostname| hostname.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn assign_fails_for_a_cordoned_host_named_explicitly() {
+        let descriptor = r#"
+            name = "pinned"
+
+            [hosts.alpha]
+            command = "true"
+            "#
+        .parse::<ExperimentDescriptor>()
+        .unwrap();
+        let available = vec![("alpha".to_string(), attributes(&[]))]
+            .into_iter()
+            .collect();
+        assert!(descriptor
+            .assign(&available, &hostnames(&["alpha"]))
+            .is_err());
+        assert_eq!(
+            descriptor.assign(&available, &hostnames(&[])).unwrap().0,
+            vec!["alpha".to_string()]
+        );
+    }
+
+    #[test]
+    fn assign_skips_cordoned_hosts_for_roles() {
+        let descriptor = r#"
+            name = "roles"
+
+            [roles.server]
+            count = 1
+            command = "true"
+            "#
+        .parse::<ExperimentDescriptor>()
+        .unwrap();
+        let available = vec![
+            ("alpha".to_string(), attributes(&[])),
+            ("beta".to_string(), attributes(&[])),
+        ]
+        .into_iter()
+        .collect();
+        let (participants, roles) = descriptor
+            .assign(&available, &hostnames(&["alpha"]))
+            .unwrap();
+        assert_eq!(participants, vec!["beta".to_string()]);
+        assert_eq!(roles.get("beta").map(String::as_str), Some("server"));
+        assert!(descriptor
+            .assign(&available, &hostnames(&["alpha", "beta"]))
+            .is_err());
+    }
+
+    fn record(participants: &[&str]) -> InvocationRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": "00000000-0000-0000-0000-000000000000",
+            "url": "",
+            "name": null,
+            "commit": "",
+            "start": "2020-01-01T00:00:00Z",
+            "participants": participants,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn host_timeouts_apply_to_experiment_wide_commands() {
+        let descriptor = r#"
+            name = "timeouts"
+            timeout = 30
+
+            [setup]
+            command = "true"
+            timeout = 5
+
+            [run]
+            command = "true"
+
+            [hosts.alpha]
+            timeout = 10
+
+            [hosts.alpha.run]
+            command = "true"
+
+            [hosts.beta]
+            "#
+        .parse::<ExperimentDescriptor>()
+        .unwrap();
+        let record = record(&["alpha", "beta"]);
+        let timeouts = |hostname, phase| {
+            descriptor
+                .commands_for(&record, hostname, phase)
+                .unwrap()
+                .into_iter()
+                .map(|(_, _, timeout)| timeout)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timeouts("alpha", Phase::Run), vec![Some(10), Some(10)]);
+        assert_eq!(timeouts("alpha", Phase::Setup), vec![Some(5)]);
+        assert_eq!(timeouts("beta", Phase::Run), vec![Some(30)]);
+        assert!(descriptor
+            .commands_for(&record, "gamma", Phase::Run)
+            .is_none());
+    }
+
+    #[test]
+    fn combinations_cover_the_matrix() {
+        let descriptor = r#"
+            name = "sweep"
+
+            [matrix]
+            size = [1, 2]
+            mode = ["fast", "slow"]
+            "#
+        .parse::<ExperimentDescriptor>()
+        .unwrap();
+        let expected = vec![
+            attributes(&[("mode", "fast"), ("size", "1")]),
+            attributes(&[("mode", "fast"), ("size", "2")]),
+            attributes(&[("mode", "slow"), ("size", "1")]),
+            attributes(&[("mode", "slow"), ("size", "2")]),
+        ];
+        assert_eq!(descriptor.combinations(), expected);
+    }
+
+    #[test]
+    fn combinations_without_a_matrix() {
+        let descriptor = r#"name = "single""#.parse::<ExperimentDescriptor>().unwrap();
+        assert!(descriptor.combinations().is_empty());
+    }
+}
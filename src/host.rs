@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use std::collections::{BTreeMap, VecDeque};
 use std::{fmt, time};
 
 use rocket::http::RawStr;
@@ -8,13 +9,16 @@ use rocket::request::FromParam;
 use uuid::Uuid;
 
 use crate::invocation::*;
+use crate::telemetry::Telemetry;
 
 const TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// How many telemetry samples are kept for each host.
+const TELEMETRY_WINDOW: usize = 60;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct HostId(Uuid);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Host {
     id: HostId,
     #[serde(skip)]
@@ -22,6 +26,28 @@ pub struct Host {
     timestamp: time::Instant,
     hostname: String,
     state: HostState,
+    /// Whether the host has been taken out of rotation, so that it takes part in no new
+    /// invocations (but finishes any it is running).
+    #[serde(default)]
+    cordoned: bool,
+    /// Whether the host has been asked to abandon the invocation it is running. Only ever set on
+    /// cordoned hosts, and cleared once the host stops running.
+    #[serde(default)]
+    draining: bool,
+    /// Labels given to the host by whoever runs it, such as its rack or purpose.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// Facts detected by the host itself, such as its architecture and CPU count.
+    #[serde(default)]
+    facts: BTreeMap<String, String>,
+    /// The SHA-256 digest of the enrolment token the host registered with, to which it is bound
+    /// so that no other host can act as it.
+    #[serde(default)]
+    enrolment: Option<String>,
+    /// The most recent telemetry samples sent by the host, oldest first. Served separately, as it
+    /// is too large to send (or journal) with every host.
+    #[serde(skip)]
+    telemetry: VecDeque<Telemetry>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -75,6 +101,12 @@ impl Host {
             hostname: hostname.to_string(),
             timestamp: time::Instant::now(),
             state: HostState::Idle,
+            cordoned: false,
+            draining: false,
+            labels: BTreeMap::new(),
+            facts: BTreeMap::new(),
+            enrolment: None,
+            telemetry: VecDeque::new(),
         }
     }
 
@@ -87,6 +119,10 @@ impl Host {
     }
 
     pub fn set_state(&mut self, state: HostState) {
+        match state {
+            HostState::Running { .. } => (),
+            _ => self.draining = false,
+        }
         self.state = state
     }
 
@@ -98,6 +134,34 @@ impl Host {
         &self.hostname
     }
 
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    pub fn facts(&self) -> &BTreeMap<String, String> {
+        &self.facts
+    }
+
+    /// Replaces the host's labels and facts, returning whether either changed.
+    pub fn describe(
+        &mut self,
+        labels: BTreeMap<String, String>,
+        facts: BTreeMap<String, String>,
+    ) -> bool {
+        let changed = self.labels != labels || self.facts != facts;
+        self.labels = labels;
+        self.facts = facts;
+        changed
+    }
+
+    /// Everything known about the host for matching against requirements: its facts, overridden
+    /// by any labels of the same name.
+    pub fn attributes(&self) -> BTreeMap<String, String> {
+        let mut attributes = self.facts.clone();
+        attributes.extend(self.labels.clone());
+        attributes
+    }
+
     pub fn current_invocation(&self) -> Option<InvocationId> {
         match self.state {
             HostState::Running { id }
@@ -109,6 +173,73 @@ impl Host {
         }
     }
 
+    /// Whether the host is connected and free to take part in a new invocation.
+    pub fn available(&self) -> bool {
+        match self.state {
+            HostState::Idle | HostState::Errored { .. } | HostState::Done { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn cordoned(&self) -> bool {
+        self.cordoned
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining
+    }
+
+    pub fn enrolment(&self) -> Option<&str> {
+        self.enrolment.as_ref().map(String::as_str)
+    }
+
+    /// Binds the host to the digest of the enrolment token it registered with, returning whether
+    /// anything changed.
+    pub fn enrol(&mut self, enrolment: &str) -> bool {
+        let changed = self.enrolment() != Some(enrolment);
+        self.enrolment = Some(enrolment.to_string());
+        changed
+    }
+
+    /// Takes the host out of rotation (or puts it back), returning whether anything changed.
+    /// Uncordoning a host also stops it draining.
+    pub fn cordon(&mut self, cordoned: bool) -> bool {
+        let changed = self.cordoned != cordoned || (!cordoned && self.draining);
+        self.cordoned = cordoned;
+        self.draining &= cordoned;
+        changed
+    }
+
+    /// Cordons the host and, if it is running an invocation, asks it to abandon it. Returns
+    /// whether anything changed.
+    pub fn drain(&mut self) -> bool {
+        let draining = match self.state {
+            HostState::Running { .. } => true,
+            _ => false,
+        };
+        let changed = !self.cordoned || self.draining != draining;
+        self.cordoned = true;
+        self.draining = draining;
+        changed
+    }
+
+    /// Adds a telemetry sample, forgetting the oldest if the window is full.
+    pub fn record(&mut self, telemetry: Telemetry) {
+        if self.telemetry.len() >= TELEMETRY_WINDOW {
+            self.telemetry.pop_front();
+        }
+        self.telemetry.push_back(telemetry);
+    }
+
+    pub fn telemetry(&self) -> &VecDeque<Telemetry> {
+        &self.telemetry
+    }
+
+    /// How long it has been since the host was last heard from.
+    pub fn since_heartbeat(&self) -> time::Duration {
+        self.timestamp.elapsed()
+    }
+
     pub fn expired(&self) -> bool {
         time::Instant::now() > self.timestamp + TIMEOUT
     }
@@ -2,13 +2,14 @@ use chrono::{DateTime, Utc};
 
 use crate::descriptor::{ExperimentDescriptor, ExperimentParseError};
 use crate::host::Host;
+use crate::report::{HostReport, Outcome, PhaseReport, Termination};
 
 use rocket::http::RawStr;
-use rocket::request::FromParam;
+use rocket::request::{FromFormValue, FromParam};
 
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use std::path::{Path, PathBuf};
 
@@ -17,7 +18,7 @@ use uuid::Uuid;
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct InvocationId(Uuid);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Invocation {
     id: InvocationId,
     url: String,
@@ -25,6 +26,41 @@ pub struct Invocation {
     descriptor: Option<ExperimentDescriptor>,
     start: DateTime<Utc>,
     logs: HashMap<String, PathBuf>,
+    #[serde(default)]
+    phases: HashMap<String, Vec<PhaseReport>>,
+    #[serde(default)]
+    reports: HashMap<String, HostReport>,
+    #[serde(default)]
+    outcome: Option<Outcome>,
+    /// The invocation whose matrix this invocation was expanded from, if any.
+    #[serde(default)]
+    parent: Option<InvocationId>,
+    /// The invocations this invocation's matrix was expanded into, if any.
+    #[serde(default)]
+    children: Vec<InvocationId>,
+    /// The point in the matrix this invocation runs (empty for invocations without a matrix).
+    #[serde(default)]
+    parameters: BTreeMap<String, String>,
+    /// The hostnames of every host taking part, decided when the invocation starts running.
+    #[serde(default)]
+    participants: Vec<String>,
+    /// The roles assigned to participating hosts, keyed by hostname.
+    #[serde(default)]
+    roles: BTreeMap<String, String>,
+    /// Who cancelled the invocation and why, if it was cancelled.
+    #[serde(default)]
+    cancellation: Option<Cancellation>,
+    /// Why the invocation failed without any host running it, if it did.
+    #[serde(default)]
+    failure: Option<String>,
+}
+
+/// The circumstances in which an invocation was cancelled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cancellation {
+    reason: Option<String>,
+    requester: Option<String>,
+    time: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,6 +70,20 @@ pub struct InvocationRecord {
     name: Option<String>,
     commit: String,
     start: DateTime<Utc>,
+    #[serde(default)]
+    outcome: Option<Outcome>,
+    #[serde(default)]
+    parent: Option<InvocationId>,
+    #[serde(default)]
+    parameters: BTreeMap<String, String>,
+    #[serde(default)]
+    participants: Vec<String>,
+    #[serde(default)]
+    roles: BTreeMap<String, String>,
+    #[serde(default)]
+    cancellation: Option<Cancellation>,
+    #[serde(default)]
+    failure: Option<String>,
 }
 
 impl<'a> FromParam<'a> for InvocationId {
@@ -49,6 +99,14 @@ impl<'a> FromParam<'a> for InvocationId {
     }
 }
 
+impl<'v> FromFormValue<'v> for InvocationId {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        InvocationId::from_param(value)
+    }
+}
+
 impl Invocation {
     pub fn new<P: AsRef<Path>>(
         url: &str,
@@ -56,9 +114,9 @@ impl Invocation {
         path: P,
     ) -> (Invocation, Option<ExperimentParseError>) {
         let descriptor = ExperimentDescriptor::load_from(path);
-        let (descriptor, err) = match descriptor {
-            Ok(descriptor) => (Some(descriptor), None),
-            Err(err) => (None, Some(err)),
+        let (descriptor, outcome, err) = match descriptor {
+            Ok(descriptor) => (Some(descriptor), None, None),
+            Err(err) => (None, Some(Outcome::Failed), Some(err)),
         };
         (
             Invocation {
@@ -68,6 +126,16 @@ impl Invocation {
                 descriptor,
                 start: Utc::now(),
                 logs: HashMap::new(),
+                phases: HashMap::new(),
+                reports: HashMap::new(),
+                outcome,
+                parent: None,
+                children: vec![],
+                parameters: BTreeMap::new(),
+                participants: vec![],
+                roles: BTreeMap::new(),
+                cancellation: None,
+                failure: None,
             },
             err,
         )
@@ -85,15 +153,221 @@ impl Invocation {
         &self.commit
     }
 
+    pub fn parent(&self) -> Option<InvocationId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[InvocationId] {
+        &self.children
+    }
+
+    /// The point in the matrix this invocation runs (empty for invocations without a matrix).
+    pub fn parameters(&self) -> &BTreeMap<String, String> {
+        &self.parameters
+    }
+
+    /// Fixes the point in the matrix the invocation runs, as when a single child of a matrix is
+    /// re-invoked on its own.
+    pub fn set_parameters(&mut self, parameters: BTreeMap<String, String>) {
+        self.parameters = parameters;
+    }
+
+    /// Expands the invocation's matrix (if it has one) into a child invocation for each
+    /// combination of parameters, which are returned in the order they should be run. Invocations
+    /// that already run a single combination aren't expanded.
+    pub fn expand(&mut self) -> Vec<Invocation> {
+        if !self.parameters.is_empty() {
+            return vec![];
+        }
+        let combinations = match self.descriptor {
+            Some(ref descriptor) => descriptor.combinations(),
+            None => vec![],
+        };
+        let children = combinations
+            .into_iter()
+            .map(|parameters| Invocation {
+                id: InvocationId(Uuid::new_v4()),
+                url: self.url.clone(),
+                commit: self.commit.clone(),
+                descriptor: self.descriptor.clone(),
+                start: Utc::now(),
+                logs: HashMap::new(),
+                phases: HashMap::new(),
+                reports: HashMap::new(),
+                outcome: None,
+                parent: Some(self.id),
+                children: vec![],
+                parameters,
+                participants: vec![],
+                roles: BTreeMap::new(),
+                cancellation: None,
+                failure: None,
+            })
+            .collect::<Vec<_>>();
+        self.children = children.iter().map(|child| child.id).collect();
+        children
+    }
+
+    /// Derives the outcome of a parent invocation from the outcomes of its children, once all of
+    /// them are known. Returns whether the outcome changed.
+    pub fn aggregate(&mut self, outcomes: &[Option<Outcome>]) -> bool {
+        if self.outcome.is_some() || outcomes.iter().any(Option::is_none) {
+            return false;
+        }
+        let count = |expected| {
+            outcomes
+                .iter()
+                .filter(|outcome| **outcome == Some(expected))
+                .count()
+        };
+        let (succeeded, cancelled) = (count(Outcome::Succeeded), count(Outcome::Cancelled));
+        self.outcome = Some(if succeeded == outcomes.len() {
+            Outcome::Succeeded
+        } else if cancelled == outcomes.len() {
+            Outcome::Cancelled
+        } else if count(Outcome::TimedOut) > 0 {
+            Outcome::TimedOut
+        } else if succeeded == 0 {
+            Outcome::Failed
+        } else {
+            Outcome::PartiallyFailed
+        });
+        true
+    }
+
     pub fn host_has_logged(&self, hostname: &str) -> bool {
         self.logs.contains_key(hostname)
     }
 
+    /// The log archives uploaded so far, keyed by hostname.
+    pub fn logs(&self) -> &HashMap<String, PathBuf> {
+        &self.logs
+    }
+
+    /// The log archive uploaded by the named host, if any.
+    pub fn log(&self, hostname: &str) -> Option<&Path> {
+        self.logs.get(hostname).map(PathBuf::as_path)
+    }
+
     pub fn add_log<P: AsRef<Path>>(&mut self, host: &Host, path: P) {
         self.logs
             .insert(host.hostname().to_string(), path.as_ref().to_path_buf());
     }
 
+    /// The hostnames of every host taking part in the invocation (empty until it is assigned).
+    pub fn participants(&self) -> &[String] {
+        &self.participants
+    }
+
+    /// Decides which hosts take part in the invocation and which roles they fill, given every host
+    /// currently available (keyed by hostname, along with its attributes) and the hostnames of
+    /// those that are cordoned.
+    pub fn assign(
+        &mut self,
+        available: &BTreeMap<String, BTreeMap<String, String>>,
+        cordoned: &BTreeSet<String>,
+    ) {
+        if let Some(ref descriptor) = self.descriptor {
+            match descriptor.assign(available, cordoned) {
+                Ok((participants, roles)) => {
+                    self.participants = participants;
+                    self.roles = roles;
+                }
+                // Running without every role filled would only fail in more confusing ways
+                Err(reason) => {
+                    self.fail(&reason);
+                    return;
+                }
+            }
+            // With nobody to run it, the invocation would otherwise never get an outcome
+            if self.participants.is_empty() && self.outcome.is_none() {
+                self.fail("no hosts were available to take part");
+            }
+        }
+    }
+
+    /// Marks the invocation as failed without any host having run it, for the given reason.
+    fn fail(&mut self, reason: &str) {
+        self.outcome = Some(Outcome::Failed);
+        self.failure = Some(reason.to_string());
+    }
+
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_ref().map(String::as_str)
+    }
+
+    pub fn add_phase_report(&mut self, host: &Host, report: PhaseReport) {
+        self.phases
+            .entry(host.hostname().to_string())
+            .or_insert_with(Vec::new)
+            .push(report);
+    }
+
+    pub fn add_report(&mut self, host: &Host, report: HostReport) {
+        self.reports.insert(host.hostname().to_string(), report);
+        if self.outcome.is_none() {
+            self.outcome = self.resolve();
+        }
+    }
+
+    /// Marks the invocation as cancelled, unless every participating host has already reported.
+    pub fn cancel(&mut self, cancellation: Cancellation) {
+        if self.outcome.is_none() {
+            self.outcome = Some(Outcome::Cancelled);
+            self.cancellation = Some(cancellation);
+        }
+    }
+
+    pub fn cancellation(&self) -> Option<&Cancellation> {
+        self.cancellation.as_ref()
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
+
+    /// Settles the outcome of an invocation that is no longer waited on, counting every
+    /// participant that never reported as having disconnected. Returns whether the outcome
+    /// changed.
+    pub fn conclude(&mut self) -> bool {
+        if self.outcome.is_some() {
+            return false;
+        }
+        if self.participants.is_empty() {
+            self.fail("no hosts took part");
+            return true;
+        }
+        for hostname in self.participants.iter() {
+            let start = self.start;
+            self.reports
+                .entry(hostname.clone())
+                .or_insert_with(|| HostReport::new(Termination::Disconnected, start));
+        }
+        self.outcome = self.resolve();
+        true
+    }
+
+    fn resolve(&self) -> Option<Outcome> {
+        let participants = self.participants();
+        let (mut succeeded, mut timed_out) = (0, false);
+        for hostname in participants.iter() {
+            match self.reports.get(hostname) {
+                Some(report) if report.succeeded() => succeeded += 1,
+                Some(report) => timed_out |= report.timed_out(),
+                None => return None,
+            }
+        }
+        Some(if succeeded == participants.len() {
+            Outcome::Succeeded
+        } else if timed_out {
+            Outcome::TimedOut
+        } else if succeeded == 0 {
+            Outcome::Failed
+        } else {
+            Outcome::PartiallyFailed
+        })
+    }
+
     pub fn record(&self) -> InvocationRecord {
         InvocationRecord {
             id: self.id,
@@ -105,6 +379,13 @@ impl Invocation {
             },
             commit: self.commit.to_string(),
             start: self.start,
+            outcome: self.outcome,
+            parent: self.parent,
+            parameters: self.parameters.clone(),
+            participants: self.participants.clone(),
+            roles: self.roles.clone(),
+            cancellation: self.cancellation.clone(),
+            failure: self.failure.clone(),
         }
     }
 
@@ -117,11 +398,41 @@ impl Invocation {
     }
 }
 
+impl Cancellation {
+    pub fn new(reason: Option<String>, requester: Option<String>) -> Cancellation {
+        Cancellation {
+            reason,
+            requester,
+            time: Utc::now(),
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_ref().map(String::as_str)
+    }
+
+    pub fn requester(&self) -> Option<&str> {
+        self.requester.as_ref().map(String::as_str)
+    }
+}
+
 impl InvocationRecord {
     pub fn id(&self) -> InvocationId {
         self.id
     }
 
+    pub fn parameters(&self) -> &BTreeMap<String, String> {
+        &self.parameters
+    }
+
+    pub fn participants(&self) -> &[String] {
+        &self.participants
+    }
+
+    pub fn role(&self, hostname: &str) -> Option<&str> {
+        self.roles.get(hostname).map(String::as_str)
+    }
+
     pub fn url(&self) -> &str {
         &self.url
     }
@@ -136,3 +447,78 @@ impl fmt::Display for InvocationId {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invocation(descriptor: &str) -> Invocation {
+        Invocation {
+            id: InvocationId(Uuid::new_v4()),
+            url: String::new(),
+            commit: String::new(),
+            descriptor: Some(descriptor.parse().unwrap()),
+            start: Utc::now(),
+            logs: HashMap::new(),
+            phases: HashMap::new(),
+            reports: HashMap::new(),
+            outcome: None,
+            parent: None,
+            children: vec![],
+            parameters: BTreeMap::new(),
+            participants: vec![],
+            roles: BTreeMap::new(),
+            cancellation: None,
+            failure: None,
+        }
+    }
+
+    fn succeeded() -> HostReport {
+        HostReport::new(Termination::Exited { code: 0 }, Utc::now())
+    }
+
+    #[test]
+    fn conclude_counts_a_host_that_disconnected_mid_run() {
+        let mut invocation = invocation(
+            r#"
+            name = "pair"
+
+            [hosts.alpha]
+            command = "true"
+
+            [hosts.beta]
+            command = "true"
+            "#,
+        );
+        invocation.assign(&BTreeMap::new(), &BTreeSet::new());
+        assert_eq!(invocation.participants(), ["alpha", "beta"]);
+        invocation.add_report(&Host::new("alpha"), succeeded());
+        // Beta disconnected before reporting, so nothing can be resolved yet
+        assert_eq!(invocation.outcome(), None);
+        assert!(invocation.conclude());
+        assert_eq!(invocation.outcome(), Some(Outcome::PartiallyFailed));
+        assert_eq!(
+            invocation.reports["beta"].termination(),
+            &Termination::Disconnected
+        );
+        // Concluding again changes nothing
+        assert!(!invocation.conclude());
+    }
+
+    #[test]
+    fn conclude_leaves_a_resolved_outcome_alone() {
+        let mut invocation = invocation(
+            r#"
+            name = "single"
+
+            [hosts.alpha]
+            command = "true"
+            "#,
+        );
+        invocation.assign(&BTreeMap::new(), &BTreeSet::new());
+        invocation.add_report(&Host::new("alpha"), succeeded());
+        assert_eq!(invocation.outcome(), Some(Outcome::Succeeded));
+        assert!(!invocation.conclude());
+        assert_eq!(invocation.outcome(), Some(Outcome::Succeeded));
+    }
+}
@@ -12,6 +12,13 @@ use std::path::Path;
 pub mod descriptor;
 pub mod host;
 pub mod invocation;
+pub mod process;
+pub mod report;
+pub mod telemetry;
+pub mod upload;
+
+/// The header in which clients supply the hex-encoded SHA-256 digest of uploaded logs.
+pub const CHECKSUM_HEADER: &str = "X-Checksum-SHA256";
 
 pub fn clone<P: AsRef<Path>>(url: &str, path: P) -> Result<Repository, git2::Error> {
     info!("cloning {}", url);
@@ -31,21 +38,35 @@ pub fn clone<P: AsRef<Path>>(url: &str, path: P) -> Result<Repository, git2::Err
     Ok(repo)
 }
 
+/// Resolves a branch, tag or (possibly abbreviated) commit hash to the full hash of a commit.
+/// Branches that have not been checked out locally are looked up on `origin`.
+pub fn resolve(repo: &Repository, rev: &str) -> Result<String, git2::Error> {
+    repo.revparse_single(rev)
+        .or_else(|_| repo.revparse_single(&format!("origin/{}", rev)))
+        .and_then(|object| object.peel_to_commit())
+        .map(|commit| format!("{}", commit.id()))
+}
+
 pub fn rewind(repo: &Repository, commit: &str) -> Result<(), git2::Error> {
-    info!("fetching origin/master...");
-    match repo.find_remote("origin") {
-        // To update, fetch and reset --hard
-        Ok(mut remote) => match remote.fetch(&["refs/heads/*:refs/heads/*"], None, None)
-            .and_then(|_| repo.head())
-            .map(|head_ref| head_ref.target().unwrap())
-            .and_then(|head| repo.find_object(head, None))
-            .and_then(|obj| repo.reset(&obj, ResetType::Hard, None))
-        {
-            Ok(_) => info!("fetched origin/master"),
-            _ => warn!("failed to reset to FETCH_HEAD"),
-        },
-        _ => info!("failed to fetch origin/master"),
+    info!("fetching origin...");
+    // Fetch every branch and tag, as the commit may not be reachable from the default branch
+    match repo.find_remote("origin").and_then(|mut remote| {
+        remote.fetch(
+            &[
+                "refs/heads/*:refs/remotes/origin/*",
+                "refs/tags/*:refs/tags/*",
+            ],
+            None,
+            None,
+        )
+    }) {
+        Ok(_) => info!("fetched origin"),
+        _ => warn!("failed to fetch origin"),
     }
+    checkout(repo, commit)
+}
+
+pub fn checkout(repo: &Repository, commit: &str) -> Result<(), git2::Error> {
     // Find the commit we want to rewind to
     let object = commit
         .parse::<Oid>()
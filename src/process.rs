@@ -0,0 +1,193 @@
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{sysconf, Pid, SysconfVar};
+
+use std::fs;
+use std::ops::Add;
+use std::process::{Child, ExitStatus};
+use std::{thread, time};
+
+/// How often to check whether a process has exited while waiting on it.
+const WAIT_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// The process group, session and parent of a process, as read from `/proc/<pid>/stat`.
+#[derive(Copy, Clone, Debug)]
+pub struct Stat {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub group: Pid,
+    pub session: Pid,
+}
+
+impl Stat {
+    pub fn read(pid: Pid) -> Option<Stat> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // The command name is parenthesised and may itself contain spaces or parentheses
+        let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(1);
+        let mut next = || fields.next()?.parse::<i32>().ok().map(Pid::from_raw);
+        Some(Stat {
+            pid,
+            parent: next()?,
+            group: next()?,
+            session: next()?,
+        })
+    }
+}
+
+/// The resources used by one or more processes, as read from `/proc/<pid>`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Usage {
+    pub processes: usize,
+    /// The CPU time spent in user and kernel mode, including by children that have exited and
+    /// been waited on, in seconds.
+    pub cpu: f64,
+    /// The resident set size, in bytes.
+    pub rss: u64,
+    /// The bytes read from storage.
+    pub read: u64,
+    /// The bytes written to storage.
+    pub written: u64,
+}
+
+impl Usage {
+    pub fn read(pid: Pid) -> Option<Usage> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // Fields are counted from the state, which follows the command name
+        let fields = stat[stat.rfind(')')? + 1..]
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+        let ticks = field(11)? + field(12)? + field(13)? + field(14)?;
+        let (read, written) = io(pid).unwrap_or((0, 0));
+        Some(Usage {
+            processes: 1,
+            cpu: ticks as f64 / config(SysconfVar::CLK_TCK, 100) as f64,
+            rss: field(21)? * config(SysconfVar::PAGE_SIZE, 4096),
+            read,
+            written,
+        })
+    }
+
+    /// The combined usage of every process in the given session.
+    pub fn of_session(session: Pid) -> Usage {
+        processes()
+            .into_iter()
+            .filter(|stat| stat.session == session)
+            .filter_map(|stat| Usage::read(stat.pid))
+            .fold(Usage::default(), Add::add)
+    }
+}
+
+impl Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            processes: self.processes + other.processes,
+            cpu: self.cpu + other.cpu,
+            rss: self.rss + other.rss,
+            read: self.read + other.read,
+            written: self.written + other.written,
+        }
+    }
+}
+
+/// The bytes a process has read from and written to storage, which only its owner may see.
+fn io(pid: Pid) -> Option<(u64, u64)> {
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).ok()?;
+    let field = |name: &str| {
+        io.lines()
+            .find(|line| line.starts_with(name))?
+            .split_whitespace()
+            .nth(1)?
+            .parse::<u64>()
+            .ok()
+    };
+    Some((field("read_bytes:")?, field("write_bytes:")?))
+}
+
+/// A system configuration value, or the given default if it can't be read.
+fn config(name: SysconfVar, default: u64) -> u64 {
+    match sysconf(name) {
+        Ok(Some(value)) if value > 0 => value as u64,
+        _ => default,
+    }
+}
+
+/// Every process currently running.
+pub fn processes() -> Vec<Stat> {
+    fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+                .filter_map(|pid| Stat::read(Pid::from_raw(pid)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The IDs of every process group in the given session.
+pub fn groups_in(session: Pid) -> Vec<Pid> {
+    let mut groups = processes()
+        .into_iter()
+        .filter(|stat| stat.session == session)
+        .map(|stat| stat.group)
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|group| group.as_raw());
+    groups.dedup();
+    groups
+}
+
+/// Sends a signal to every process group in the given session, returning whether any were found.
+pub fn signal_session(session: Pid, signal: Signal) -> bool {
+    let groups = groups_in(session);
+    for group in groups.iter() {
+        signal::killpg(*group, signal).unwrap_or(());
+    }
+    !groups.is_empty()
+}
+
+/// Waits for a child to exit, for at most `timeout` (or indefinitely if `None`). If the child is
+/// still running at the end of the timeout, its process group is sent SIGTERM and, should it
+/// still be running `grace` later, SIGKILL. Returns the exit status, along with whether the child
+/// timed out.
+pub fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<time::Duration>,
+    grace: time::Duration,
+) -> std::io::Result<(ExitStatus, bool)> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(|status| (status, false)),
+    };
+    if let Some(status) = wait_until(child, time::Instant::now() + timeout)? {
+        return Ok((status, false));
+    }
+    let group = Pid::from_raw(child.id() as i32);
+    warn!(
+        "command timed out after {}s, terminating...",
+        timeout.as_secs()
+    );
+    signal::killpg(group, Signal::SIGTERM).unwrap_or(());
+    if let Some(status) = wait_until(child, time::Instant::now() + grace)? {
+        return Ok((status, true));
+    }
+    warn!(
+        "command still running after {}s, killing...",
+        grace.as_secs()
+    );
+    signal::killpg(group, Signal::SIGKILL).unwrap_or(());
+    child.wait().map(|status| (status, true))
+}
+
+fn wait_until(child: &mut Child, deadline: time::Instant) -> std::io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let now = time::Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(std::cmp::min(WAIT_INTERVAL, deadline - now));
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+
+use crate::descriptor::Phase;
+
+use serde::{Deserialize, Serialize};
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// How a command run by a host terminated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "desc")]
+pub enum Termination {
+    /// The command exited with the given status code.
+    #[serde(rename = "exited")]
+    Exited { code: i32 },
+    /// The command was terminated by the given signal.
+    #[serde(rename = "signalled")]
+    Signalled { signal: i32 },
+    /// The command ran for longer than its timeout, in seconds, and was terminated.
+    #[serde(rename = "timed_out")]
+    TimedOut { timeout: u64 },
+    /// The command was still running when the invocation was cancelled. `clean` is whether it
+    /// exited within the grace period given to it, rather than having to be killed.
+    #[serde(rename = "cancelled")]
+    Cancelled { clean: bool },
+    /// The command could not be started.
+    #[serde(rename = "failed")]
+    Failed { msg: String },
+    /// The host disconnected (or otherwise gave up on the invocation) without reporting how it
+    /// terminated, and the invocation was concluded without it.
+    #[serde(rename = "disconnected")]
+    Disconnected,
+}
+
+/// The result of executing an invocation on a single host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostReport {
+    termination: Termination,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// Wall-clock time taken, in milliseconds.
+    duration: i64,
+}
+
+/// The result of running a single phase of an invocation on a host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhaseReport {
+    phase: Phase,
+    termination: Termination,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// Wall-clock time taken, in milliseconds.
+    duration: i64,
+}
+
+/// The overall result of an invocation, derived once every participating host has reported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// Every participating host succeeded.
+    #[serde(rename = "succeeded")]
+    Succeeded,
+    /// Some, but not all, participating hosts failed.
+    #[serde(rename = "partially_failed")]
+    PartiallyFailed,
+    /// Every participating host failed, or the invocation could not be started at all.
+    #[serde(rename = "failed")]
+    Failed,
+    /// At least one participating host timed out.
+    #[serde(rename = "timed_out")]
+    TimedOut,
+    /// The invocation was cancelled before every participating host reported.
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+impl Termination {
+    pub fn success(&self) -> bool {
+        match self {
+            Termination::Exited { code } => *code == 0,
+            _ => false,
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        match self {
+            Termination::TimedOut { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<ExitStatus> for Termination {
+    fn from(status: ExitStatus) -> Termination {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Termination::Exited { code },
+            (None, Some(signal)) => Termination::Signalled { signal },
+            // One of the two is always present on Unix
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+impl PhaseReport {
+    pub fn new(phase: Phase, termination: Termination, start: DateTime<Utc>) -> PhaseReport {
+        let end = Utc::now();
+        PhaseReport {
+            phase,
+            termination,
+            start,
+            end,
+            duration: (end - start).num_milliseconds(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn termination(&self) -> &Termination {
+        &self.termination
+    }
+}
+
+impl HostReport {
+    pub fn new(termination: Termination, start: DateTime<Utc>) -> HostReport {
+        let end = Utc::now();
+        HostReport {
+            termination,
+            start,
+            end,
+            duration: (end - start).num_milliseconds(),
+        }
+    }
+
+    pub fn termination(&self) -> &Termination {
+        &self.termination
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.termination.success()
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.termination.timed_out()
+    }
+}
@@ -0,0 +1,392 @@
+use chrono::Utc;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::Outcome;
+
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+/// The name of the file describing the invocation in a merged archive.
+const MANIFEST: &str = "manifest.json";
+/// How many chunks of a merged archive may be buffered before the thread building it waits for
+/// them to be sent.
+const MERGE_BUFFER: usize = 16;
+
+/// A file within an uploaded log archive.
+#[derive(Serialize)]
+pub struct ArchiveEntry {
+    path: PathBuf,
+    size: u64,
+}
+
+/// The byte range requested by a `Range` header, if any. Only a single range is supported; the
+/// bounds are as given in the header, so either (but not both) may be missing. Requests for
+/// several ranges, or for ranges in any unit other than bytes, are ignored, so the whole file is
+/// sent.
+pub struct Range(Option<(Option<u64>, Option<u64>)>);
+
+/// A file read out of a log archive, or the requested range of it.
+pub struct Slice {
+    reader: io::Take<GzDecoder<File>>,
+    name: PathBuf,
+    /// The first and last bytes of the slice, along with the size of the whole file, if only part
+    /// of it was requested.
+    range: Option<(u64, u64, u64)>,
+}
+
+/// A single archive containing the logs of every host, built on the fly by a background thread
+/// and read as it is built. If building it fails part way through, reading it fails too, so the
+/// response is abandoned rather than ended as though the archive were complete.
+pub struct Merged {
+    name: String,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    buf: io::Cursor<Vec<u8>>,
+}
+
+/// Hands what is written to it to a `Merged`, chunk by chunk.
+struct ChunkWriter(SyncSender<io::Result<Vec<u8>>>);
+
+/// Why a file (or a merged archive) couldn't be read out of the log archives.
+pub enum SliceError {
+    /// The archive doesn't contain the file.
+    NotFound,
+    /// The requested range lies outside the file, whose size is given.
+    Unsatisfiable(u64),
+    Io(io::Error),
+}
+
+impl From<io::Error> for SliceError {
+    fn from(err: io::Error) -> SliceError {
+        SliceError::Io(err)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Range {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Range, ()> {
+        match request.headers().get_one("Range").map(Range::parse) {
+            Some(Some(range)) => Outcome::Success(range),
+            Some(None) => Outcome::Failure((Status::RangeNotSatisfiable, ())),
+            None => Outcome::Success(Range(None)),
+        }
+    }
+}
+
+impl Range {
+    /// Parses the value of a `Range` header, returning `None` if it asks for a single range of
+    /// bytes that is syntactically invalid.
+    fn parse(header: &str) -> Option<Range> {
+        let mut parts = header.trim().splitn(2, '=');
+        let spec = match (parts.next(), parts.next()) {
+            (Some(unit), Some(spec))
+                if unit.trim().eq_ignore_ascii_case("bytes") && !spec.contains(',') =>
+            {
+                spec
+            }
+            _ => return Some(Range(None)),
+        };
+        let bounds = spec
+            .splitn(2, '-')
+            .map(|bound| match bound.trim() {
+                "" => Ok(None),
+                bound => bound.parse::<u64>().map(Some),
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match bounds.as_ref().map(Vec::as_slice) {
+            Ok([Some(first), last]) if last.map(|last| last >= *first).unwrap_or(true) => {
+                Some(Range(Some((Some(*first), *last))))
+            }
+            Ok([None, Some(suffix)]) => Some(Range(Some((None, Some(*suffix))))),
+            _ => None,
+        }
+    }
+
+    /// Resolves the range against a file of the given size, returning the first and last bytes to
+    /// be read, or `None` if the range lies outside the file.
+    fn within(&self, size: u64) -> Option<Option<(u64, u64)>> {
+        match self.0 {
+            None => Some(None),
+            Some((Some(first), _)) if first >= size => None,
+            Some((Some(first), last)) => Some(Some((
+                first,
+                last.map(|last| last.min(size - 1)).unwrap_or(size - 1),
+            ))),
+            Some((None, Some(suffix))) if suffix > 0 && size > 0 => {
+                Some(Some((size.saturating_sub(suffix), size - 1)))
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Slice {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+        if let Some(extension) = self.name.extension().and_then(|ext| ext.to_str()) {
+            response.header(ContentType::from_extension(extension).unwrap_or(ContentType::Plain));
+        } else {
+            response.header(ContentType::Plain);
+        }
+        if let Some((first, last, size)) = self.range {
+            response.status(Status::PartialContent).raw_header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", first, last, size),
+            );
+        }
+        response.streamed_body(self.reader).ok()
+    }
+}
+
+impl<'r> Responder<'r> for Merged {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Content-Type", "application/gzip")
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.name),
+            )
+            .streamed_body(self)
+            .ok()
+    }
+}
+
+impl Read for Merged {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.buf.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => self.buf = io::Cursor::new(chunk),
+                Ok(Err(err)) => return Err(err),
+                // The archive is complete once the thread building it hangs up
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map(|_| buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "download abandoned"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'r> Responder<'r> for SliceError {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        match self {
+            SliceError::NotFound => Err(Status::NotFound),
+            SliceError::Unsatisfiable(size) => Response::build()
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", size))
+                .ok(),
+            SliceError::Io(err) => {
+                warn!("failed to read log archive: {}", err);
+                Err(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+/// Every file in the archive at the given path, in the order they were archived.
+pub fn entries<P: AsRef<Path>>(path: P) -> io::Result<Vec<ArchiveEntry>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            entries.push(ArchiveEntry {
+                path: normalise(&entry.path()?),
+                size: entry.size(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads the named file (or the requested range of it) out of the archive at the given path.
+/// As archives are compressed as a whole, everything before the file is decompressed to find it,
+/// but nothing is held in memory.
+pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    name: Q,
+    range: &Range,
+) -> Result<Slice, SliceError> {
+    let name = normalise(name.as_ref());
+    let (position, size) = {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&path)?));
+        let mut found = None;
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type().is_file() && normalise(&entry.path()?) == name {
+                found = Some((entry.raw_file_position(), entry.size()));
+                break;
+            }
+        }
+        found.ok_or(SliceError::NotFound)?
+    };
+    let range = range.within(size);
+    let (first, length) = match range {
+        Some(Some((first, last))) => (first, last - first + 1),
+        Some(None) => (0, size),
+        None => return Err(SliceError::Unsatisfiable(size)),
+    };
+    // Decompress afresh, skipping straight over everything before the requested bytes
+    let mut decoder = GzDecoder::new(File::open(&path)?);
+    io::copy(&mut (&mut decoder).take(position + first), &mut io::sink())?;
+    Ok(Slice {
+        reader: decoder.take(length),
+        name,
+        range: match range {
+            Some(Some((first, last))) => Some((first, last, size)),
+            _ => None,
+        },
+    })
+}
+
+/// Merges the given log archives, keyed by hostname, into a single archive with a directory for
+/// each host, alongside a manifest. The archive is built as it is read, so nothing is held in
+/// memory or written to disk. Every archive is opened up front, so that a missing one fails the
+/// request before anything is sent.
+pub fn merge(name: String, manifest: Vec<u8>, logs: Vec<(String, PathBuf)>) -> io::Result<Merged> {
+    let logs = logs
+        .into_iter()
+        .map(|(hostname, path)| File::open(path).map(|file| (hostname, file)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let (sender, chunks) = mpsc::sync_channel(MERGE_BUFFER);
+    let failed = sender.clone();
+    thread::spawn(move || {
+        let encoder = GzEncoder::new(ChunkWriter(sender), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        let result = append_manifest(&mut builder, &manifest)
+            .and_then(|_| {
+                logs.into_iter()
+                    .map(|(hostname, file)| append_log(&mut builder, &hostname, file))
+                    .collect::<io::Result<()>>()
+            })
+            .and_then(|_| builder.into_inner())
+            .and_then(|encoder| encoder.finish())
+            .map(|_| ());
+        if let Err(err) = result {
+            warn!("failed to build merged log archive: {}", err);
+            let _ = failed.send(Err(err));
+        }
+    });
+    Ok(Merged {
+        name,
+        chunks,
+        buf: io::Cursor::new(vec![]),
+    })
+}
+
+fn append_manifest<W: Write>(builder: &mut tar::Builder<W>, manifest: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, MANIFEST, manifest)
+}
+
+/// Appends every file and directory in a host's log archive under a directory named after it.
+fn append_log<W: Write>(
+    builder: &mut tar::Builder<W>,
+    hostname: &str,
+    file: File,
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            continue;
+        }
+        let path = Path::new(hostname).join(normalise(&entry.path()?));
+        let mut header = entry.header().clone();
+        builder.append_data(&mut header, path, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Strips any leading `./` (and similar) from a path within an archive, so that it can be
+/// compared against the paths given by users.
+fn normalise(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| match component {
+            Component::Normal(_) => true,
+            _ => false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(header: &str) -> Option<Option<(Option<u64>, Option<u64>)>> {
+        Range::parse(header).map(|range| range.0)
+    }
+
+    #[test]
+    fn parse_single_byte_ranges() {
+        assert_eq!(bounds("bytes=0-99"), Some(Some((Some(0), Some(99)))));
+        assert_eq!(bounds("bytes=100-"), Some(Some((Some(100), None))));
+        assert_eq!(bounds("bytes=-50"), Some(Some((None, Some(50)))));
+        assert_eq!(bounds(" Bytes = 5 - 5 "), Some(Some((Some(5), Some(5)))));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_byte_ranges() {
+        assert_eq!(bounds("bytes=9-5"), None);
+        assert_eq!(bounds("bytes=-"), None);
+        assert_eq!(bounds("bytes=a-b"), None);
+    }
+
+    #[test]
+    fn parse_ignores_other_units_and_several_ranges() {
+        assert_eq!(bounds("lines=0-10"), Some(None));
+        assert_eq!(bounds("bytes=0-1,5-6"), Some(None));
+        assert_eq!(bounds("nonsense"), Some(None));
+    }
+
+    #[test]
+    fn within_resolves_against_the_size() {
+        assert_eq!(Range(None).within(10), Some(None));
+        assert_eq!(
+            Range(Some((Some(2), Some(5)))).within(10),
+            Some(Some((2, 5)))
+        );
+        // Ranges running past the end are cut short
+        assert_eq!(
+            Range(Some((Some(2), Some(50)))).within(10),
+            Some(Some((2, 9)))
+        );
+        assert_eq!(Range(Some((Some(2), None))).within(10), Some(Some((2, 9))));
+        assert_eq!(Range(Some((None, Some(3)))).within(10), Some(Some((7, 9))));
+        assert_eq!(Range(Some((None, Some(30)))).within(10), Some(Some((0, 9))));
+        // Ranges starting past the end, or asking for nothing, can't be satisfied
+        assert_eq!(Range(Some((Some(10), None))).within(10), None);
+        assert_eq!(Range(Some((None, Some(0)))).within(10), None);
+        assert_eq!(Range(Some((None, Some(3)))).within(0), None);
+    }
+}
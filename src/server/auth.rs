@@ -0,0 +1,153 @@
+use cluster::host::Host;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+
+use sha2::{Digest, Sha256};
+
+use std::collections::{HashMap, HashSet};
+
+/// The tokens accepted by the server, read from the `host_tokens` (an array of enrolment tokens),
+/// `api_tokens` and `admin_tokens` (tables of user names to tokens) extras in the Rocket
+/// configuration. If `host_tokens` or `api_tokens` is missing, the routes it guards are open to
+/// anyone. If `admin_tokens` is missing, every user is an admin. Each enrolment token is bound to
+/// the first host to register with it, so every host needs a token of its own.
+pub struct Tokens {
+    hosts: Option<HashSet<String>>,
+    /// User names, keyed by token.
+    users: Option<HashMap<String, String>>,
+    /// Admin names, keyed by token.
+    admins: Option<HashMap<String, String>>,
+}
+
+/// Guards routes used by hosts, which must present an enrolment token. Holds the SHA-256 digest of
+/// the token, unless host tokens are not configured. Routes acting on a particular host must also
+/// check that the token is the one it registered with.
+pub struct HostAuth(Option<String>);
+
+/// Guards routes that start or stop experiments, which must be called with a user's API token.
+/// Holds the name of the user, unless API tokens are not configured.
+pub struct UserAuth(Option<String>);
+
+/// Guards routes that manage the cluster itself (such as cordoning hosts), which must be called
+/// with an admin's token. Holds the name of the admin, unless no tokens are configured.
+pub struct AdminAuth(Option<String>);
+
+impl Tokens {
+    pub fn from_config(config: &Config) -> Tokens {
+        let hosts = config.get_slice("host_tokens").ok().map(|tokens| {
+            tokens
+                .iter()
+                .filter_map(|token| token.as_str())
+                .map(str::to_string)
+                .collect::<HashSet<_>>()
+        });
+        let names = |key: &str| {
+            config.get_table(key).ok().map(|tokens| {
+                tokens
+                    .iter()
+                    .filter_map(|(name, token)| Some((token.as_str()?.to_string(), name.clone())))
+                    .collect::<HashMap<_, _>>()
+            })
+        };
+        let (users, admins) = (names("api_tokens"), names("admin_tokens"));
+        if hosts.is_none() {
+            warn!("no host_tokens configured, so any host may register");
+        }
+        if users.is_none() {
+            warn!("no api_tokens configured, so anyone may start or stop experiments");
+        }
+        if admins.is_none() {
+            warn!("no admin_tokens configured, so every user may cordon and drain hosts");
+        }
+        Tokens {
+            hosts,
+            users,
+            admins,
+        }
+    }
+}
+
+impl HostAuth {
+    /// The digest of the token presented, to which a host registering with it is bound.
+    pub fn enrolment(&self) -> Option<&str> {
+        self.0.as_ref().map(String::as_str)
+    }
+
+    /// Whether the token presented is the one the given host registered with.
+    pub fn is(&self, host: &Host) -> bool {
+        self.0.is_none() || self.enrolment() == host.enrolment()
+    }
+}
+
+impl UserAuth {
+    /// The name of the authenticated user, if API tokens are configured.
+    pub fn name(&self) -> Option<&str> {
+        self.0.as_ref().map(String::as_str)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for HostAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<HostAuth, ()> {
+        let tokens = request.guard::<State<Tokens>>()?;
+        match tokens.hosts {
+            None => Outcome::Success(HostAuth(None)),
+            Some(ref hosts) => match bearer(request) {
+                Some(token) if hosts.contains(token) => {
+                    let digest = format!("{:x}", Sha256::digest(token.as_bytes()));
+                    Outcome::Success(HostAuth(Some(digest)))
+                }
+                _ => Outcome::Failure((Status::Unauthorized, ())),
+            },
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<UserAuth, ()> {
+        let tokens = request.guard::<State<Tokens>>()?;
+        match tokens.users {
+            None => Outcome::Success(UserAuth(None)),
+            Some(ref users) => match bearer(request).and_then(|token| users.get(token)) {
+                Some(name) => Outcome::Success(UserAuth(Some(name.clone()))),
+                None => Outcome::Failure((Status::Unauthorized, ())),
+            },
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AdminAuth, ()> {
+        let tokens = request.guard::<State<Tokens>>()?;
+        match tokens.admins {
+            None => request.guard::<UserAuth>().map(|user| AdminAuth(user.0)),
+            Some(ref admins) => match bearer(request).and_then(|token| admins.get(token)) {
+                Some(name) => Outcome::Success(AdminAuth(Some(name.clone()))),
+                None => Outcome::Failure((Status::Unauthorized, ())),
+            },
+        }
+    }
+}
+
+/// The token presented in the request's `Authorization: Bearer` header, if any.
+fn bearer<'a>(request: &'a Request) -> Option<&'a str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| {
+            let mut parts = header.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(token.trim())
+                }
+                _ => None,
+            }
+        })
+}
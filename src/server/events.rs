@@ -0,0 +1,195 @@
+use cluster::host::{Host, HostId, HostState};
+use cluster::invocation::{Cancellation, InvocationId, InvocationRecord};
+use cluster::report::Outcome;
+
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+use serde::Serialize;
+
+use crate::wait::{Waiter, MAX_WAIT};
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time;
+
+/// How many events may wait to be sent to a subscriber before it is assumed to have stalled and
+/// is disconnected.
+const EVENT_BUFFER: usize = 256;
+/// How long a subscription may go without an event before a comment is sent to keep it alive
+/// (and to notice if the subscriber has gone away).
+const KEEPALIVE: time::Duration = time::Duration::from_secs(15);
+/// The largest chunk written at once. Each chunk holds a single frame, so this only matters for
+/// frames larger than it, which are split.
+const CHUNK_SIZE: u64 = 1 << 16;
+/// Sent at the start of every subscription, telling the browser how soon to reconnect (in
+/// milliseconds) once the subscription ends.
+const RETRY: &[u8] = b"retry: 1000\n\n";
+
+/// A change to the state of the instance, as sent to subscribers.
+#[derive(Serialize)]
+#[serde(tag = "event")]
+pub enum Event<'a> {
+    /// A host registered for the first time.
+    #[serde(rename = "host_registered")]
+    HostRegistered { host: &'a Host },
+    /// A host moved into a new state (including when it re-registers).
+    #[serde(rename = "host_state")]
+    HostStateChanged {
+        id: HostId,
+        hostname: &'a str,
+        state: HostState,
+    },
+    /// A host stopped sending heartbeats and is now assumed disconnected.
+    #[serde(rename = "host_disconnected")]
+    HostDisconnected { id: HostId, hostname: &'a str },
+    /// A host was cordoned, drained or put back into rotation.
+    #[serde(rename = "host_cordoned")]
+    HostCordoned {
+        id: HostId,
+        hostname: &'a str,
+        cordoned: bool,
+        draining: bool,
+    },
+    /// An invocation was created (and queued, unless it has a matrix).
+    #[serde(rename = "invocation_created")]
+    InvocationCreated { invocation: InvocationRecord },
+    /// An invocation left the queue and became current.
+    #[serde(rename = "invocation_started")]
+    InvocationStarted {
+        id: InvocationId,
+        participants: &'a [String],
+    },
+    /// An invocation was cancelled, either while running or while queued.
+    #[serde(rename = "invocation_cancelled")]
+    InvocationCancelled {
+        id: InvocationId,
+        cancellation: &'a Cancellation,
+    },
+    /// Every participating host reported on an invocation (or, for an invocation with a matrix,
+    /// every child finished).
+    #[serde(rename = "invocation_finished")]
+    InvocationFinished { id: InvocationId, outcome: Outcome },
+    /// A host uploaded its logs for an invocation.
+    #[serde(rename = "log_uploaded")]
+    LogUploaded { id: InvocationId, hostname: &'a str },
+}
+
+/// Everyone subscribed to events, each of whom is sent every event as a ready-made frame.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<SyncSender<String>>>,
+}
+
+/// A stream of events in the `text/event-stream` format, for as long as the subscriber keeps up
+/// or until `MAX_WAIT` has passed, after which the subscriber reconnects.
+pub struct Subscription<'r> {
+    frames: Receiver<String>,
+    buf: io::Cursor<Vec<u8>>,
+    /// Whether any of the frame in `buf` has been read, so that the chunk holding it should end
+    /// once it has all been read.
+    sent: bool,
+    deadline: time::Instant,
+    _waiter: Waiter<'r>,
+}
+
+impl<'a> Event<'a> {
+    /// The name of the event, as given in the `event` field of each frame.
+    fn name(&self) -> &'static str {
+        match self {
+            Event::HostRegistered { .. } => "host_registered",
+            Event::HostStateChanged { .. } => "host_state",
+            Event::HostDisconnected { .. } => "host_disconnected",
+            Event::HostCordoned { .. } => "host_cordoned",
+            Event::InvocationCreated { .. } => "invocation_created",
+            Event::InvocationStarted { .. } => "invocation_started",
+            Event::InvocationCancelled { .. } => "invocation_cancelled",
+            Event::InvocationFinished { .. } => "invocation_finished",
+            Event::LogUploaded { .. } => "log_uploaded",
+        }
+    }
+}
+
+impl Events {
+    /// Subscribes to events, holding the given place to wait for as long as the subscription is.
+    pub fn subscribe<'r>(&self, waiter: Waiter<'r>) -> Subscription<'r> {
+        let (sender, frames) = mpsc::sync_channel(EVENT_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        Subscription {
+            frames,
+            buf: io::Cursor::new(RETRY.to_vec()),
+            sent: false,
+            deadline: time::Instant::now() + MAX_WAIT,
+            _waiter: waiter,
+        }
+    }
+
+    /// Sends an event to every subscriber, dropping any that have gone away or fallen too far
+    /// behind (who can reconnect and fetch the current state afresh).
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let data = match serde_json::to_string(&event) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("failed to serialise {} event: {}", event.name(), err);
+                return;
+            }
+        };
+        let frame = format!("event: {}\ndata: {}\n\n", event.name(), data);
+        subscribers.retain(|subscriber| match subscriber.try_send(frame.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("dropping event subscriber that has fallen behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Rocket keeps reading into a chunk until it is full or a read returns nothing, and a chunk that
+/// is empty ends the response. Reads therefore return nothing once after each frame, so that it
+/// is written out in a chunk of its own, and block for the next frame otherwise.
+impl<'r> Read for Subscription<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.buf.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.sent |= read > 0;
+                return Ok(read);
+            }
+            if self.sent {
+                self.sent = false;
+                return Ok(0);
+            }
+            let now = time::Instant::now();
+            if now >= self.deadline {
+                return Ok(0);
+            }
+            match self.frames.recv_timeout(KEEPALIVE.min(self.deadline - now)) {
+                Ok(frame) => self.buf = io::Cursor::new(frame.into_bytes()),
+                Err(RecvTimeoutError::Timeout) if time::Instant::now() >= self.deadline => {
+                    return Ok(0)
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.buf = io::Cursor::new(b": keepalive\n\n".to_vec())
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Subscription<'r> {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Content-Type", "text/event-stream")
+            .raw_header("Cache-Control", "no-cache")
+            .chunked_body(self, CHUNK_SIZE)
+            .ok()
+    }
+}
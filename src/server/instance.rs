@@ -1,21 +1,44 @@
 use cluster::host::*;
 use cluster::invocation::*;
+use cluster::report::{HostReport, PhaseReport};
 
 use git2::Repository;
 
-use std::collections::HashMap;
+use crate::events::{Event, Events, Subscription};
+use crate::journal::{Entry, Journal};
+use crate::metrics::Metrics;
+use crate::wait::Waiter;
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::{fmt, thread, time};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{fmt, io, thread, time};
 
 pub struct Instance {
     hosts: Arc<Mutex<HashMap<HostId, Host>>>,
     invocation: Mutex<Option<InvocationId>>,
+    /// Signalled whenever the current invocation changes.
+    changed: Condvar,
+    queue: Mutex<VecDeque<InvocationId>>,
     invocations: Mutex<HashMap<InvocationId, Invocation>>,
+    barriers: Mutex<HashMap<(InvocationId, String), Barrier>>,
+    journal: Mutex<Journal>,
+    events: Arc<Events>,
+    metrics: Metrics,
     path: PathBuf,
 }
 
+/// A named synchronisation point within an invocation. Once every participating host has arrived
+/// the barrier is released, and stays released for any late arrivals.
+#[derive(Default, Serialize)]
+pub struct Barrier {
+    arrived: HashSet<String>,
+    released: bool,
+}
+
 #[derive(Debug)]
 pub struct InstanceError {
     cause: Option<Box<dyn Error>>,
@@ -37,6 +60,14 @@ pub enum InstanceErrorKind {
     MissingCommits,
     /// The supplied invocation or host ID was invalid.
     InvalidId,
+    /// The supplied branch, tag or commit could not be found in the repository.
+    UnknownRevision,
+    /// The supplied invocation is not waiting in the queue.
+    NotQueued,
+    /// The supplied hostname cannot be used as a single path component.
+    InvalidHostname,
+    /// The supplied enrolment token is bound to another host.
+    WrongHost,
 }
 
 impl fmt::Display for InstanceErrorKind {
@@ -60,6 +91,20 @@ impl fmt::Display for InstanceErrorKind {
             InstanceErrorKind::InvalidId => {
                 write!(f, "the supplied invocation or host ID was invalid")
             }
+            InstanceErrorKind::UnknownRevision => write!(
+                f,
+                "the supplied branch, tag or commit could not be found in the repository"
+            ),
+            InstanceErrorKind::NotQueued => {
+                write!(f, "the supplied invocation is not waiting in the queue")
+            }
+            InstanceErrorKind::InvalidHostname => write!(
+                f,
+                "the supplied hostname cannot be used as a single path component"
+            ),
+            InstanceErrorKind::WrongHost => {
+                write!(f, "the supplied enrolment token is bound to another host")
+            }
         }
     }
 }
@@ -86,24 +131,59 @@ impl From<InstanceErrorKind> for InstanceError {
 }
 
 impl Instance {
-    pub fn new<P: AsRef<Path>>(path: P) -> Instance {
-        let hosts = Arc::new(Mutex::new(HashMap::new()));
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(path: P, data_dir: Q) -> io::Result<Instance> {
+        let (journal, entries) = Journal::open(data_dir)?;
+        let mut hosts = HashMap::new();
+        let mut invocations = HashMap::new();
+        let mut current = None;
+        let mut queue = VecDeque::new();
+        for entry in entries {
+            match entry {
+                Entry::Host { mut host } => {
+                    // Hosts will have to re-register before they are considered connected
+                    host.set_state(HostState::Disconnected);
+                    hosts.insert(host.id(), host);
+                }
+                Entry::Invocation { invocation } => {
+                    invocations.insert(invocation.id(), invocation);
+                }
+                Entry::Current { id } => current = id,
+                Entry::Queue { queue: ids } => queue = ids.into_iter().collect(),
+            }
+        }
+        info!(
+            "restored {} hosts and {} invocations from journal",
+            hosts.len(),
+            invocations.len()
+        );
+        let hosts = Arc::new(Mutex::new(hosts));
+        let events = Arc::new(Events::default());
         let instance = Instance {
             hosts: Arc::clone(&hosts),
-            invocation: Mutex::new(None),
-            invocations: Mutex::new(HashMap::new()),
+            invocation: Mutex::new(current),
+            changed: Condvar::new(),
+            queue: Mutex::new(queue),
+            invocations: Mutex::new(invocations),
+            barriers: Mutex::new(HashMap::new()),
+            journal: Mutex::new(journal),
+            events: Arc::clone(&events),
+            metrics: Metrics::default(),
             path: path.as_ref().to_path_buf(),
         };
         thread::spawn(move || loop {
             thread::sleep(time::Duration::from_millis(200));
             let mut hosts = hosts.lock().unwrap();
-            for (_, host) in hosts.iter_mut() {
-                if host.expired() {
-                    host.set_state(HostState::Disconnected)
+            for (id, host) in hosts.iter_mut() {
+                if host.expired() && host.state() != HostState::Disconnected {
+                    host.set_state(HostState::Disconnected);
+                    events.emit(Event::HostDisconnected {
+                        id: *id,
+                        hostname: host.hostname(),
+                    });
                 }
             }
         });
-        instance
+        Ok(instance)
     }
 
     #[inline]
@@ -145,6 +225,15 @@ impl Instance {
         f(&mut iter.map(|(_, invocation)| invocation))
     }
 
+    /// Subscribes to every change made to the instance from now on.
+    pub fn subscribe<'r>(&self, waiter: Waiter<'r>) -> Subscription<'r> {
+        self.events.subscribe(waiter)
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     pub fn current_invocation(&self) -> Option<InvocationId> {
         match *self.invocation.lock().unwrap() {
             Some(ref invocation) => Some(*invocation),
@@ -152,38 +241,273 @@ impl Instance {
         }
     }
 
-    pub fn register(&self, hostname: &str) -> Result<HostId, InstanceError> {
+    /// Waits until the current invocation is something other than `seen`, returning whether it
+    /// changed before the timeout.
+    pub fn wait_for_change(&self, seen: Option<InvocationId>, timeout: time::Duration) -> bool {
+        let deadline = time::Instant::now() + timeout;
+        let mut current = self.invocation.lock().unwrap();
+        while *current == seen {
+            let now = time::Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            current = self
+                .changed
+                .wait_timeout(current, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// The records of every invocation waiting to run, in the order they will be run.
+    pub fn queued(&self) -> Vec<InvocationRecord> {
+        let queue = self.queue.lock().unwrap();
+        let invocations = self.invocations.lock().unwrap();
+        queue
+            .iter()
+            .filter_map(|id| invocations.get(id))
+            .map(|invocation| invocation.record())
+            .collect()
+    }
+
+    /// Moves a queued invocation to the given position in the queue (or to the back if the
+    /// position is past the end of the queue).
+    pub fn reorder(&self, id: InvocationId, position: usize) -> Result<(), InstanceError> {
+        let mut queue = self.queue.lock().unwrap();
+        let index = queue
+            .iter()
+            .position(|queued| *queued == id)
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::NotQueued))?;
+        queue.remove(index);
+        let position = position.min(queue.len());
+        queue.insert(position, id);
+        self.journal(Entry::Queue {
+            queue: queue.iter().cloned().collect(),
+        });
+        Ok(())
+    }
+
+    /// Removes an invocation from the queue, marking it as cancelled. Given the parent of a matrix
+    /// (which is never queued itself), every child still in the queue is removed instead.
+    pub fn dequeue(
+        &self,
+        id: InvocationId,
+        cancellation: Cancellation,
+    ) -> Result<(), InstanceError> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut invocations = self.invocations.lock().unwrap();
+        let removed = if queue.contains(&id) {
+            vec![id]
+        } else {
+            invocations
+                .get(&id)
+                .map(|parent| parent.children().to_vec())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|child| queue.contains(child))
+                .collect()
+        };
+        if removed.is_empty() {
+            return Err(InstanceErrorKind::NotQueued.into());
+        }
+        queue.retain(|queued| !removed.contains(queued));
+        for id in removed {
+            if let Some(invocation) = invocations.get_mut(&id) {
+                self.cancel_invocation(invocation, cancellation.clone());
+            }
+            self.propagate(&mut invocations, id);
+        }
+        self.journal(Entry::Queue {
+            queue: queue.iter().cloned().collect(),
+        });
+        Ok(())
+    }
+
+    /// Records that the given host has arrived at the named barrier, returning whether the barrier
+    /// has been released.
+    pub fn arrive(
+        &self,
+        id: InvocationId,
+        name: &str,
+        host: HostId,
+    ) -> Result<bool, InstanceError> {
+        let hostname = self
+            .host(host, |host| host.hostname().to_string())
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        let participants = self
+            .invocation(id, |invocation| invocation.participants().to_vec())
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        let mut barriers = self.barriers.lock().unwrap();
+        let barrier = barriers
+            .entry((id, name.to_string()))
+            .or_insert_with(Barrier::default);
+        barrier.arrived.insert(hostname);
+        if !barrier.released {
+            let hosts = self.hosts.lock().unwrap();
+            let arrived = &barrier.arrived;
+            // Hosts that have already left the invocation (e.g. because they were drained) will
+            // never arrive
+            barrier.released = participants.iter().all(|hostname| {
+                arrived.contains(hostname)
+                    || connected(&hosts, hostname).map_or(true, |host| left(host, id))
+            });
+            if barrier.released {
+                info!("released barrier {} for invocation {}", name, id);
+            }
+        }
+        Ok(barrier.released)
+    }
+
+    pub fn barrier<F, T>(&self, id: InvocationId, name: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&Barrier) -> T,
+    {
+        self.barriers
+            .lock()
+            .unwrap()
+            .get(&(id, name.to_string()))
+            .map(f)
+    }
+
+    pub fn set_state(&self, id: HostId, state: HostState) -> Option<()> {
+        self.host(id, |host| {
+            host.refresh();
+            // Hosts send their state with every heartbeat, so only changes are worth announcing
+            if host.state() != state {
+                let draining = host.draining();
+                host.set_state(state);
+                self.events.emit(Event::HostStateChanged {
+                    id,
+                    hostname: host.hostname(),
+                    state,
+                });
+                if draining && !host.draining() {
+                    self.announce_cordon(id, host);
+                }
+            }
+        })?;
+        self.advance();
+        Some(())
+    }
+
+    /// Registers a host (or re-registers it, if already known) with the given labels and facts.
+    /// Given the digest of the enrolment token it presented, the host is bound to that token,
+    /// which no other host may then register with (nor the host with any other).
+    pub fn register(
+        &self,
+        hostname: &str,
+        enrolment: Option<&str>,
+        labels: BTreeMap<String, String>,
+        facts: BTreeMap<String, String>,
+    ) -> Result<HostId, InstanceError> {
+        // Hostnames name the directories that hosts' streamed logs are stored in
+        if !valid_hostname(hostname) {
+            return Err(InstanceErrorKind::InvalidHostname.into());
+        }
         let mut hosts = self.hosts.lock().unwrap();
+        if let Some(enrolment) = enrolment {
+            let taken = hosts.values().any(|host| match host.enrolment() {
+                Some(other) if host.hostname() == hostname => other != enrolment,
+                Some(other) => other == enrolment,
+                None => false,
+            });
+            if taken {
+                return Err(InstanceErrorKind::WrongHost.into());
+            }
+        }
         for (id, host) in hosts.iter_mut() {
             if hostname == host.hostname() {
                 host.refresh();
                 host.set_state(HostState::Idle);
+                let enrolled = enrolment.map_or(false, |enrolment| host.enrol(enrolment));
+                if host.describe(labels, facts) || enrolled {
+                    self.journal(Entry::Host { host: host.clone() });
+                }
+                self.events.emit(Event::HostStateChanged {
+                    id: *id,
+                    hostname,
+                    state: HostState::Idle,
+                });
                 return Ok(*id);
             }
         }
-        let host = Host::new(hostname);
+        let mut host = Host::new(hostname);
+        host.describe(labels, facts);
+        if let Some(enrolment) = enrolment {
+            host.enrol(enrolment);
+        }
         let id = host.id();
+        self.journal(Entry::Host { host: host.clone() });
+        self.events.emit(Event::HostRegistered { host: &host });
         hosts.insert(id, host);
         Ok(id)
     }
 
-    pub fn invoke(&self, url: &str) -> Result<InvocationId, InstanceError> {
+    /// Takes the given host out of rotation, or puts it back. A cordoned host finishes whatever
+    /// it is running but takes part in no new invocations.
+    pub fn cordon(&self, id: HostId, cordoned: bool) -> Option<()> {
+        self.host(id, |host| {
+            if host.cordon(cordoned) {
+                info!(
+                    "{} host {}",
+                    if cordoned { "cordoned" } else { "uncordoned" },
+                    host.hostname()
+                );
+                self.announce_cordon(id, host);
+            }
+        })
+    }
+
+    /// Cordons the given host and asks it to abandon the invocation it is running, which it is
+    /// told in reply to its next heartbeat. The rest of the participants carry on without it.
+    pub fn drain(&self, id: HostId) -> Option<()> {
+        self.host(id, |host| {
+            if host.drain() {
+                info!("draining host {}", host.hostname());
+                self.announce_cordon(id, host);
+            }
+        })
+    }
+
+    /// Invokes the repository at the given URL, either at `HEAD` or at the given branch, tag or
+    /// commit.
+    pub fn invoke(&self, url: &str, rev: Option<&str>) -> Result<InvocationId, InstanceError> {
         let repo = self.clone(url)?;
-        let commit = repo
-            .head()
-            .and_then(|head| head.resolve())
-            .and_then(|resolved| resolved.peel_to_commit())
-            .and_then(|commit| Ok(format!("{}", commit.id())))
-            .map_err(|err| InstanceError {
-                cause: Some(Box::new(err)),
-                kind: InstanceErrorKind::MissingCommits,
-            })?;
-        self.build_invocation(url, &commit)
+        let commit = match rev {
+            Some(rev) => {
+                let commit = cluster::resolve(&repo, rev).map_err(|err| InstanceError {
+                    cause: Some(Box::new(err)),
+                    kind: InstanceErrorKind::UnknownRevision,
+                })?;
+                cluster::checkout(&repo, &commit).map_err(|err| InstanceError {
+                    cause: Some(Box::new(err)),
+                    kind: InstanceErrorKind::MissingCommits,
+                })?;
+                commit
+            }
+            None => repo
+                .head()
+                .and_then(|head| head.resolve())
+                .and_then(|resolved| resolved.peel_to_commit())
+                .and_then(|commit| Ok(format!("{}", commit.id())))
+                .map_err(|err| InstanceError {
+                    cause: Some(Box::new(err)),
+                    kind: InstanceErrorKind::MissingCommits,
+                })?,
+        };
+        self.build_invocation(url, &commit, BTreeMap::new())
     }
 
+    /// Invokes the same commit as the given invocation again. Re-invoking a child of a matrix
+    /// runs only that child's combination of parameters, rather than the whole matrix.
     pub fn reinvoke(&self, id: InvocationId) -> Result<InvocationId, InstanceError> {
-        let (url, commit) = match self.invocations.lock().unwrap().get(&id) {
-            Some(old) => (old.url().to_string(), old.commit().to_string()),
+        let (url, commit, parameters) = match self.invocations.lock().unwrap().get(&id) {
+            Some(old) => (
+                old.url().to_string(),
+                old.commit().to_string(),
+                old.parameters().clone(),
+            ),
             _ => return Err(InstanceErrorKind::InvalidId.into()),
         };
         let repo = self.clone(&url)?;
@@ -191,31 +515,515 @@ impl Instance {
             cause: Some(Box::new(err)),
             kind: InstanceErrorKind::CloningFailed,
         })?;
-        self.build_invocation(&url, &commit)
+        self.build_invocation(&url, &commit, parameters)
     }
 
-    pub fn cancel(&self) {
-        *self.invocation.lock().unwrap() = None;
+    /// Cancels the current invocation, recording who requested it and why. Hosts still running
+    /// the invocation will notice that it is no longer current and terminate it.
+    pub fn cancel(&self, cancellation: Cancellation) {
+        let current = self.invocation.lock().unwrap().take();
+        self.changed.notify_all();
+        if let Some(id) = current {
+            info!(
+                "cancelling invocation {} (requested by {}: {})",
+                id,
+                cancellation.requester().unwrap_or("unknown"),
+                cancellation.reason().unwrap_or("no reason given")
+            );
+            let mut invocations = self.invocations.lock().unwrap();
+            if let Some(invocation) = invocations.get_mut(&id) {
+                self.cancel_invocation(invocation, cancellation);
+            }
+            self.propagate(&mut invocations, id);
+        }
+        self.journal(Entry::Current { id: None });
+        self.advance();
     }
 
-    fn build_invocation(&self, url: &str, commit: &str) -> Result<InvocationId, InstanceError> {
-        let (invocation, err) = Invocation::new(url, commit, &self.path);
+    pub fn add_log<P: AsRef<Path>>(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        path: P,
+    ) -> Result<(), InstanceError> {
+        let mut invocations = self.invocations.lock().unwrap();
+        let invocation = invocations
+            .get_mut(&id)
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        self.host(host, |host| {
+            invocation.add_log(host, path);
+            self.events.emit(Event::LogUploaded {
+                id,
+                hostname: host.hostname(),
+            });
+        })
+        .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        self.journal(Entry::Invocation {
+            invocation: invocation.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn report(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        report: HostReport,
+    ) -> Result<(), InstanceError> {
+        let mut invocations = self.invocations.lock().unwrap();
+        let invocation = invocations
+            .get_mut(&id)
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        let finished = invocation.outcome().is_some();
+        self.host(host, |host| invocation.add_report(host, report))
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        self.journal(Entry::Invocation {
+            invocation: invocation.clone(),
+        });
+        if let (false, Some(outcome)) = (finished, invocation.outcome()) {
+            self.events.emit(Event::InvocationFinished { id, outcome });
+        }
+        self.propagate(&mut invocations, id);
+        Ok(())
+    }
+
+    pub fn report_phase(
+        &self,
+        id: InvocationId,
+        host: HostId,
+        report: PhaseReport,
+    ) -> Result<(), InstanceError> {
+        let mut invocations = self.invocations.lock().unwrap();
+        let invocation = invocations
+            .get_mut(&id)
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        self.host(host, |host| invocation.add_phase_report(host, report))
+            .ok_or_else(|| InstanceError::from(InstanceErrorKind::InvalidId))?;
+        self.journal(Entry::Invocation {
+            invocation: invocation.clone(),
+        });
+        Ok(())
+    }
+
+    /// Creates and queues an invocation of the given commit, either of the whole matrix (if there
+    /// is one) or, if `parameters` are given, of that single combination.
+    fn build_invocation(
+        &self,
+        url: &str,
+        commit: &str,
+        parameters: BTreeMap<String, String>,
+    ) -> Result<InvocationId, InstanceError> {
+        let (mut invocation, err) = Invocation::new(url, commit, &self.path);
+        invocation.set_parameters(parameters);
         let id = invocation.id();
-        self.invocations.lock().unwrap().insert(id, invocation);
-        *self.invocation.lock().unwrap() = Some(id);
+        // Invocations with a matrix are never run themselves, only their children are
+        let children = invocation.expand();
+        let mut queued = children.iter().map(Invocation::id).collect::<Vec<_>>();
+        if queued.is_empty() {
+            queued.push(id);
+        }
+        {
+            let mut invocations = self.invocations.lock().unwrap();
+            for invocation in children.into_iter().chain(Some(invocation)) {
+                self.journal(Entry::Invocation {
+                    invocation: invocation.clone(),
+                });
+                self.events.emit(Event::InvocationCreated {
+                    invocation: invocation.record(),
+                });
+                invocations.insert(invocation.id(), invocation);
+            }
+        }
         if let Some(err) = err {
             return Err(InstanceError {
                 cause: Some(Box::new(err)),
                 kind: InstanceErrorKind::BrokenManifest,
             });
         }
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(queued);
+            self.journal(Entry::Queue {
+                queue: queue.iter().cloned().collect(),
+            });
+        }
+        self.advance();
         Ok(id)
     }
 
+    /// Updates the outcome of the given invocation's parent (if it has one) from the outcomes of
+    /// its children.
+    fn propagate(&self, invocations: &mut HashMap<InvocationId, Invocation>, id: InvocationId) {
+        let parent = match invocations.get(&id).and_then(Invocation::parent) {
+            Some(parent) => parent,
+            None => return,
+        };
+        let outcomes = match invocations.get(&parent) {
+            Some(parent) => parent
+                .children()
+                .iter()
+                .map(|child| invocations.get(child).and_then(Invocation::outcome))
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+        if let Some(parent) = invocations.get_mut(&parent) {
+            if parent.aggregate(&outcomes) {
+                self.journal(Entry::Invocation {
+                    invocation: parent.clone(),
+                });
+                if let Some(outcome) = parent.outcome() {
+                    self.events.emit(Event::InvocationFinished {
+                        id: parent.id(),
+                        outcome,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Promotes the invocation at the front of the queue if the current invocation has finished.
+    /// A finished invocation remains current until there is something to replace it, but its
+    /// outcome is settled straight away, even if some participants never reported.
+    fn advance(&self) {
+        let mut current = self.invocation.lock().unwrap();
+        if let Some(id) = *current {
+            if !self.finished(id) {
+                return;
+            }
+            self.conclude(id);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        while let Some(next) = queue.pop_front() {
+            info!("promoting invocation {}", next);
+            self.assign(next);
+            *current = Some(next);
+            self.changed.notify_all();
+            // Barriers are only ever needed by the current invocation
+            self.barriers.lock().unwrap().clear();
+            self.journal(Entry::Current { id: Some(next) });
+            self.journal(Entry::Queue {
+                queue: queue.iter().cloned().collect(),
+            });
+            // Invocations that fail as they are assigned (e.g. for want of hosts) are passed over
+            if !self.finished(next) {
+                break;
+            }
+            self.conclude(next);
+        }
+    }
+
+    /// Settles the outcome of an invocation that is no longer waited on, for want of reports from
+    /// participants that disconnected.
+    fn conclude(&self, id: InvocationId) {
+        let mut invocations = self.invocations.lock().unwrap();
+        let outcome = match invocations.get_mut(&id) {
+            Some(invocation) if invocation.conclude() => {
+                self.journal(Entry::Invocation {
+                    invocation: invocation.clone(),
+                });
+                invocation.outcome()
+            }
+            _ => return,
+        };
+        if let Some(outcome) = outcome {
+            warn!("concluding invocation {} without every report", id);
+            self.events.emit(Event::InvocationFinished { id, outcome });
+        }
+        self.propagate(&mut invocations, id);
+    }
+
+    /// Decides which hosts take part in the given invocation from those currently available. Hosts
+    /// still busy with an invocation that has already concluded (e.g. because it was cancelled)
+    /// count as available, as they will move on as soon as they notice. Cordoned hosts never take
+    /// part, even if named explicitly.
+    fn assign(&self, id: InvocationId) {
+        let mut invocations = self.invocations.lock().unwrap();
+        let (available, cordoned) = {
+            let hosts = self.hosts.lock().unwrap();
+            let available = hosts
+                .values()
+                .filter(|host| {
+                    host.available()
+                        || host
+                            .current_invocation()
+                            .and_then(|other| invocations.get(&other))
+                            .and_then(Invocation::outcome)
+                            .is_some()
+                })
+                .map(|host| (host.hostname().to_string(), host.attributes()))
+                .collect::<BTreeMap<_, _>>();
+            // Draining hosts are cordoned too
+            let cordoned = hosts
+                .values()
+                .filter(|host| host.cordoned())
+                .map(|host| host.hostname().to_string())
+                .collect::<BTreeSet<_>>();
+            (available, cordoned)
+        };
+        let mut failed = false;
+        if let Some(invocation) = invocations.get_mut(&id) {
+            invocation.assign(&available, &cordoned);
+            self.journal(Entry::Invocation {
+                invocation: invocation.clone(),
+            });
+            self.events.emit(Event::InvocationStarted {
+                id,
+                participants: invocation.participants(),
+            });
+            if let (Some(outcome), Some(failure)) = (invocation.outcome(), invocation.failure()) {
+                warn!("invocation {} failed: {}", id, failure);
+                self.events.emit(Event::InvocationFinished { id, outcome });
+                failed = true;
+            }
+        }
+        if failed {
+            self.propagate(&mut invocations, id);
+        }
+    }
+
+    /// Whether every participating host has either completed the given invocation or errored
+    /// while attempting it. Participants that are disconnected (or were never registered) are
+    /// not waited on, as they would otherwise stall the queue indefinitely (the same goes for
+    /// barriers).
+    fn finished(&self, id: InvocationId) -> bool {
+        let participants =
+            match self.invocation(id, |invocation| invocation.participants().to_vec()) {
+                Some(participants) => participants,
+                None => return true,
+            };
+        let hosts = self.hosts.lock().unwrap();
+        participants
+            .iter()
+            .all(|hostname| connected(&hosts, hostname).map_or(true, |host| left(host, id)))
+    }
+
+    /// Cancels the given invocation, unless it has already finished.
+    fn cancel_invocation(&self, invocation: &mut Invocation, cancellation: Cancellation) {
+        let finished = invocation.outcome().is_some();
+        invocation.cancel(cancellation);
+        self.journal(Entry::Invocation {
+            invocation: invocation.clone(),
+        });
+        match invocation.cancellation() {
+            Some(cancellation) if !finished => self.events.emit(Event::InvocationCancelled {
+                id: invocation.id(),
+                cancellation,
+            }),
+            _ => (),
+        }
+    }
+
+    /// Records a change to whether a host is cordoned or draining.
+    fn announce_cordon(&self, id: HostId, host: &Host) {
+        self.journal(Entry::Host { host: host.clone() });
+        self.events.emit(Event::HostCordoned {
+            id,
+            hostname: host.hostname(),
+            cordoned: host.cordoned(),
+            draining: host.draining(),
+        });
+    }
+
+    fn journal(&self, entry: Entry) {
+        if let Err(err) = self.journal.lock().unwrap().append(&entry) {
+            warn!("failed to write journal entry: {}", err);
+        }
+    }
+
     fn clone(&self, url: &str) -> Result<Repository, InstanceError> {
-        cluster::clone(url, &self.path).map_err(|err| InstanceError {
+        let start = time::Instant::now();
+        let repo = cluster::clone(url, &self.path).map_err(|err| InstanceError {
             cause: Some(Box::new(err)),
             kind: InstanceErrorKind::CloningFailed,
-        })
+        })?;
+        self.metrics.record_clone(start.elapsed());
+        Ok(repo)
+    }
+}
+
+/// Finds the host with the given hostname, provided it is registered and has not disconnected.
+fn connected<'a>(hosts: &'a HashMap<HostId, Host>, hostname: &str) -> Option<&'a Host> {
+    hosts
+        .values()
+        .find(|host| host.hostname() == hostname)
+        .filter(|host| host.state() != HostState::Disconnected)
+}
+
+/// Whether the host has completed the given invocation or errored while attempting it.
+fn left(host: &Host, id: InvocationId) -> bool {
+    match host.state() {
+        HostState::Done { id: other } | HostState::Errored { id: other } => other == id,
+        _ => false,
+    }
+}
+
+/// Whether the hostname is a single, ordinary path component (so no separators, `.` or `..`).
+fn valid_hostname(hostname: &str) -> bool {
+    let mut components = Path::new(hostname).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name == hostname && !hostname.contains('\\'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    use cluster::report::Outcome;
+
+    use serde_json::json;
+
+    use uuid::Uuid;
+
+    fn id() -> InvocationId {
+        serde_json::from_value(json!(Uuid::new_v4())).unwrap()
+    }
+
+    /// A stored invocation, as it would have been journaled.
+    fn invocation(
+        id: InvocationId,
+        participants: &[&str],
+        parent: Option<InvocationId>,
+        children: &[InvocationId],
+    ) -> Invocation {
+        serde_json::from_value(json!({
+            "id": id,
+            "url": "https://example.com/experiment.git",
+            "commit": "0000000000000000000000000000000000000000",
+            "descriptor": null,
+            "start": "2019-01-01T00:00:00Z",
+            "logs": {},
+            "participants": participants,
+            "parent": parent,
+            "children": children,
+        }))
+        .unwrap()
+    }
+
+    /// An instance restored from a journal holding the given invocations and queue.
+    fn instance(invocations: Vec<Invocation>, queue: &[InvocationId]) -> Instance {
+        let dir = scratch();
+        {
+            let (mut journal, _) = Journal::open(&dir).unwrap();
+            for invocation in invocations {
+                journal.append(&Entry::Invocation { invocation }).unwrap();
+            }
+            journal
+                .append(&Entry::Queue {
+                    queue: queue.to_vec(),
+                })
+                .unwrap();
+        }
+        Instance::new(&dir, &dir).unwrap()
+    }
+
+    fn queued(instance: &Instance) -> Vec<InvocationId> {
+        instance.queued().iter().map(InvocationRecord::id).collect()
+    }
+
+    fn outcome(instance: &Instance, id: InvocationId) -> Option<Outcome> {
+        instance
+            .invocation(id, |invocation| invocation.outcome())
+            .unwrap()
+    }
+
+    fn register(instance: &Instance, hostname: &str) -> HostId {
+        instance
+            .register(hostname, None, BTreeMap::new(), BTreeMap::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn reorder_moves_a_queued_invocation() {
+        let (a, b, c) = (id(), id(), id());
+        let instance = instance(
+            vec![
+                invocation(a, &[], None, &[]),
+                invocation(b, &[], None, &[]),
+                invocation(c, &[], None, &[]),
+            ],
+            &[a, b, c],
+        );
+        instance.reorder(c, 0).unwrap();
+        assert_eq!(queued(&instance), [c, a, b]);
+        // Positions past the end of the queue move the invocation to the back
+        instance.reorder(c, 10).unwrap();
+        assert_eq!(queued(&instance), [a, b, c]);
+        assert!(instance.reorder(id(), 0).is_err());
+        assert_eq!(queued(&instance), [a, b, c]);
+    }
+
+    #[test]
+    fn dequeue_cancels_a_queued_invocation() {
+        let (a, b) = (id(), id());
+        let instance = instance(
+            vec![invocation(a, &[], None, &[]), invocation(b, &[], None, &[])],
+            &[a, b],
+        );
+        let cancellation = Cancellation::new(Some("superseded".to_string()), None);
+        instance.dequeue(a, cancellation.clone()).unwrap();
+        assert_eq!(queued(&instance), [b]);
+        assert_eq!(outcome(&instance, a), Some(Outcome::Cancelled));
+        assert_eq!(outcome(&instance, b), None);
+        assert!(instance.dequeue(a, cancellation).is_err());
+    }
+
+    #[test]
+    fn dequeue_removes_every_queued_child_of_a_matrix() {
+        let (parent, first, second, other) = (id(), id(), id(), id());
+        let instance = instance(
+            vec![
+                invocation(parent, &[], None, &[first, second]),
+                invocation(first, &[], Some(parent), &[]),
+                invocation(second, &[], Some(parent), &[]),
+                invocation(other, &[], None, &[]),
+            ],
+            &[first, other, second],
+        );
+        instance
+            .dequeue(parent, Cancellation::new(None, None))
+            .unwrap();
+        assert_eq!(queued(&instance), [other]);
+        assert_eq!(outcome(&instance, first), Some(Outcome::Cancelled));
+        assert_eq!(outcome(&instance, second), Some(Outcome::Cancelled));
+        assert_eq!(outcome(&instance, parent), Some(Outcome::Cancelled));
+        assert_eq!(outcome(&instance, other), None);
+    }
+
+    #[test]
+    fn barrier_is_released_once_every_participant_arrives() {
+        let run = id();
+        let instance = instance(vec![invocation(run, &["alpha", "beta"], None, &[])], &[]);
+        let alpha = register(&instance, "alpha");
+        let beta = register(&instance, "beta");
+        assert!(!instance.arrive(run, "setup", alpha).unwrap());
+        // Arriving twice doesn't count for another host
+        assert!(!instance.arrive(run, "setup", alpha).unwrap());
+        assert!(instance.arrive(run, "setup", beta).unwrap());
+        // The barrier stays released for late arrivals, and other barriers are unaffected
+        assert!(instance.arrive(run, "setup", alpha).unwrap());
+        assert!(!instance.arrive(run, "teardown", alpha).unwrap());
+        assert_eq!(
+            instance.barrier(run, "setup", |barrier| barrier.arrived.len()),
+            Some(2)
+        );
+        assert!(instance.arrive(id(), "setup", alpha).is_err());
+    }
+
+    #[test]
+    fn barrier_does_not_wait_for_participants_that_are_gone() {
+        let run = id();
+        let instance = instance(
+            vec![invocation(run, &["alpha", "beta", "gamma"], None, &[])],
+            &[],
+        );
+        let alpha = register(&instance, "alpha");
+        let beta = register(&instance, "beta");
+        // Gamma never connected, and beta finishes its part before reaching the barrier
+        assert!(!instance.arrive(run, "setup", alpha).unwrap());
+        instance.host(beta, |host| host.set_state(HostState::Done { id: run }));
+        assert!(instance.arrive(run, "setup", alpha).unwrap());
     }
 }
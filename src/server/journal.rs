@@ -0,0 +1,209 @@
+use cluster::host::{Host, HostId};
+use cluster::invocation::{Invocation, InvocationId};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the journal file within the data directory.
+const JOURNAL: &str = "journal.jsonl";
+
+/// The fewest lines the journal is allowed to grow to before it is compacted while running.
+const COMPACT_MIN: usize = 1024;
+
+/// A single durable change to the state of an instance. Entries are appended to the journal as
+/// they happen and replayed in order on startup, so later entries supersede earlier ones.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "entry")]
+pub enum Entry {
+    /// A host was registered for the first time.
+    #[serde(rename = "host")]
+    Host { host: Host },
+    /// An invocation was created or modified.
+    #[serde(rename = "invocation")]
+    Invocation { invocation: Invocation },
+    /// The current invocation changed.
+    #[serde(rename = "current")]
+    Current { id: Option<InvocationId> },
+    /// The queue of pending invocations changed.
+    #[serde(rename = "queue")]
+    Queue { queue: Vec<InvocationId> },
+}
+
+/// What an entry is about. Only the latest entry with any given key needs to be kept.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Host(HostId),
+    Invocation(InvocationId),
+    Current,
+    Queue,
+}
+
+impl Entry {
+    fn key(&self) -> Key {
+        match self {
+            Entry::Host { host } => Key::Host(host.id()),
+            Entry::Invocation { invocation } => Key::Invocation(invocation.id()),
+            Entry::Current { .. } => Key::Current,
+            Entry::Queue { .. } => Key::Queue,
+        }
+    }
+}
+
+/// An append-only journal of JSON entries, one per line. Once the file holds more than twice as
+/// many lines as there are live entries (and at least `COMPACT_MIN`), it is rewritten to hold only
+/// the latest entry for each host, invocation, current invocation and queue.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// The latest serialized entry for each key, which is all a compacted journal needs.
+    live: HashMap<Key, Vec<u8>>,
+    /// How many lines the file currently holds.
+    lines: usize,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal in the given directory, returning it along with every entry
+    /// it already contains. The journal is compacted straight away.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<(Journal, Vec<Entry>)> {
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(JOURNAL);
+        let mut entries = vec![];
+        let mut live = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Entry>(&line) {
+                        Ok(entry) => {
+                            live.insert(entry.key(), line.into_bytes());
+                            entries.push(entry);
+                        }
+                        // A partially written final line is expected if the server died mid-write
+                        Err(err) => warn!("skipping unreadable journal entry: {}", err),
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let mut journal = Journal {
+            path,
+            file,
+            live,
+            lines: 0,
+        };
+        journal.compact()?;
+        Ok((journal, entries))
+    }
+
+    /// Appends an entry, compacting the journal afterwards if it has grown too large. Failing to
+    /// compact is only logged, since the entry itself has been written by then.
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        line.pop();
+        self.live.insert(entry.key(), line);
+        self.lines += 1;
+        if self.lines >= COMPACT_MIN && self.lines > 2 * self.live.len() {
+            if let Err(err) = self.compact() {
+                warn!("failed to compact journal: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the contents of the journal with the latest entry for each key.
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for line in self.live.values() {
+                file.write_all(line)?;
+                file.write_all(b"\n")?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = self.live.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    fn lines(dir: &Path) -> usize {
+        BufReader::new(File::open(dir.join(JOURNAL)).unwrap())
+            .lines()
+            .count()
+    }
+
+    fn host(host: &Host) -> Entry {
+        Entry::Host { host: host.clone() }
+    }
+
+    #[test]
+    fn replay_keeps_the_latest_entry_for_each_key() {
+        let dir = scratch();
+        let mut alpha = Host::new("alpha");
+        {
+            let (mut journal, entries) = Journal::open(&dir).unwrap();
+            assert!(entries.is_empty());
+            journal.append(&host(&alpha)).unwrap();
+            journal.append(&Entry::Current { id: None }).unwrap();
+            journal.append(&Entry::Queue { queue: vec![] }).unwrap();
+            alpha.cordon(true);
+            journal.append(&host(&alpha)).unwrap();
+            journal.append(&host(&Host::new("beta"))).unwrap();
+            assert_eq!(lines(&dir), 5);
+        }
+        let (_, entries) = Journal::open(&dir).unwrap();
+        assert_eq!(entries.len(), 5);
+        // Opening compacts away the superseded entry for alpha
+        assert_eq!(lines(&dir), 4);
+        let (_, entries) = Journal::open(&dir).unwrap();
+        assert_eq!(entries.len(), 4);
+        let hosts: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Host { host } => Some(host),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hosts.len(), 2);
+        assert!(hosts
+            .iter()
+            .any(|host| host.id() == alpha.id() && host.cordoned()));
+        assert!(hosts.iter().any(|host| host.hostname() == "beta"));
+    }
+
+    #[test]
+    fn append_compacts_once_the_journal_grows_too_large() {
+        let dir = scratch();
+        let (mut journal, _) = Journal::open(&dir).unwrap();
+        for _ in 1..COMPACT_MIN {
+            journal.append(&Entry::Current { id: None }).unwrap();
+        }
+        assert_eq!(lines(&dir), COMPACT_MIN - 1);
+        journal.append(&Entry::Current { id: None }).unwrap();
+        assert_eq!(lines(&dir), 1);
+        journal.append(&Entry::Queue { queue: vec![] }).unwrap();
+        assert_eq!(lines(&dir), 2);
+        let (_, entries) = Journal::open(&dir).unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+}
@@ -4,31 +4,55 @@
 extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
+#[macro_use]
+extern crate log;
 
+mod archive;
+mod auth;
+mod events;
 mod instance;
+mod journal;
+mod metrics;
+mod stream;
+mod upload;
+mod wait;
 
 use cluster::host::{HostId, HostState};
-use cluster::invocation::InvocationId;
-
-use multipart::server::Multipart;
-
-use rocket::data::{self, FromDataSimple};
-use rocket::http::Status;
-use rocket::{Data, Outcome, Request, State};
-
-use rocket_contrib::json::JsonValue;
+use cluster::invocation::{Cancellation, InvocationId};
+use cluster::report::{HostReport, PhaseReport};
+use cluster::telemetry::Heartbeat;
+use cluster::upload::UploadId;
+
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::response::{status::Custom, Stream};
+use rocket::{Data, Request, State};
+
+use rocket_contrib::json::{Json, JsonValue};
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
 
-use self::instance::Instance;
-
-use std::fs;
-use std::io::Cursor;
+use self::archive::{Merged, Range, Slice, SliceError};
+use self::auth::{AdminAuth, HostAuth, Tokens, UserAuth};
+use self::events::Subscription;
+use self::instance::{Instance, InstanceError, InstanceErrorKind};
+use self::stream::Follow;
+use self::upload::{LogUpload, UploadError, UploadLimit, Uploads};
+use self::wait::{Waiters, MAX_WAIT};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::time;
 
-use uuid::Uuid;
-
-const LOG_DIR: &str = "logs/";
+pub const LOG_DIR: &str = "logs/";
+/// The directory into which experiments are cloned by the server.
+const EXPERIMENT_DIR: &str = "experiment/";
+/// Where the journal is kept unless `data_dir` is set in the Rocket configuration.
+const DATA_DIR_DEFAULT: &str = "data/";
 
 macro_rules! ok {
     () => {
@@ -54,49 +78,13 @@ macro_rules! err {
     };
 }
 
-#[derive(Debug)]
-struct LogUpload(PathBuf);
-
-impl FromDataSimple for LogUpload {
-    type Error = ();
-
-    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
-        if let Some(content_type) = request.headers().get_one("Content-Type") {
-            if let Some(index) = content_type.find("boundary=") {
-                let boundary = &content_type[(index + "boundary=".len())..];
-                let mut body = vec![];
-                if data.stream_to(&mut body).is_ok() {
-                    let mut multipart = Multipart::with_body(Cursor::new(body), boundary);
-                    let mut path = None;
-                    if multipart
-                        .foreach_entry(|mut entry| {
-                            if &*entry.headers.name == "log" {
-                                let log_path = Path::new(LOG_DIR)
-                                    .join(&format!("{}", Uuid::new_v4()))
-                                    .with_extension("tar.gz");
-                                entry.data.save().memory_threshold(0).with_path(&log_path);
-                                path = Some(log_path);
-                            }
-                        })
-                        .is_ok()
-                    {
-                        if let Some(path) = path {
-                            return Outcome::Success(LogUpload(path));
-                        }
-                    }
-                }
-            }
-        }
-        Outcome::Failure((Status::InternalServerError, ()))
-    }
-}
-
 mod host {
     use super::*;
 
     #[get("/register/<hostname>")]
-    pub fn register(hostname: String, instance: State<Instance>) -> JsonValue {
-        match instance.register(&hostname) {
+    pub fn register(auth: HostAuth, hostname: String, instance: State<Instance>) -> JsonValue {
+        let enrolment = auth.enrolment();
+        match instance.register(&hostname, enrolment, BTreeMap::new(), BTreeMap::new()) {
             Ok(id) => host(id, instance),
             Err(err) => err!(err),
         }
@@ -113,53 +101,305 @@ mod host {
         use super::*;
 
         #[inline]
-        fn set_state(instance: State<Instance>, id: HostId, state: HostState) -> JsonValue {
+        fn set_state(
+            auth: HostAuth,
+            instance: State<Instance>,
+            id: HostId,
+            state: HostState,
+        ) -> JsonValue {
+            if let Err(err) = acting_as(&auth, &instance, id) {
+                return err!(err);
+            }
             instance
-                .host(id, |host| {
-                    host.refresh();
-                    host.set_state(state);
-                    ok!()
-                })
+                .set_state(id, state)
+                .map(|_| ok!())
                 .unwrap_or_else(|| err!())
         }
 
         #[get("/<host>/idle")]
-        pub fn idle(host: HostId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Idle)
+        pub fn idle(auth: HostAuth, host: HostId, instance: State<Instance>) -> JsonValue {
+            set_state(auth, instance, host, HostState::Idle)
         }
 
         #[get("/<host>/running/<id>")]
-        pub fn running(host: HostId, id: InvocationId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Running { id })
+        pub fn running(
+            auth: HostAuth,
+            host: HostId,
+            id: InvocationId,
+            instance: State<Instance>,
+        ) -> JsonValue {
+            set_state(auth, instance, host, HostState::Running { id })
         }
 
         #[get("/<host>/errored/<id>")]
-        pub fn errored(host: HostId, id: InvocationId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Errored { id })
+        pub fn errored(
+            auth: HostAuth,
+            host: HostId,
+            id: InvocationId,
+            instance: State<Instance>,
+        ) -> JsonValue {
+            set_state(auth, instance, host, HostState::Errored { id })
         }
 
         #[get("/<host>/compressing/<id>")]
-        pub fn compressing(host: HostId, id: InvocationId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Compressing { id })
+        pub fn compressing(
+            auth: HostAuth,
+            host: HostId,
+            id: InvocationId,
+            instance: State<Instance>,
+        ) -> JsonValue {
+            set_state(auth, instance, host, HostState::Compressing { id })
         }
 
         #[get("/<host>/uploading/<id>")]
-        pub fn uploading(host: HostId, id: InvocationId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Uploading { id })
+        pub fn uploading(
+            auth: HostAuth,
+            host: HostId,
+            id: InvocationId,
+            instance: State<Instance>,
+        ) -> JsonValue {
+            set_state(auth, instance, host, HostState::Uploading { id })
         }
 
         #[get("/<host>/done/<id>")]
-        pub fn done(host: HostId, id: InvocationId, instance: State<Instance>) -> JsonValue {
-            set_state(instance, host, HostState::Done { id })
+        pub fn done(
+            auth: HostAuth,
+            host: HostId,
+            id: InvocationId,
+            instance: State<Instance>,
+        ) -> JsonValue {
+            set_state(auth, instance, host, HostState::Done { id })
         }
     }
 }
 
+/// Version 2 of the API, which changes state with `POST`, `PUT` and `DELETE` requests taking JSON
+/// bodies rather than `GET` requests with everything in the URL. Routes that only read state are
+/// shared with version 1 and mounted under both.
+mod v2 {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Registration {
+        hostname: String,
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+        #[serde(default)]
+        facts: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Invoke {
+        url: String,
+        rev: Option<String>,
+    }
+
+    #[derive(Default, Deserialize)]
+    #[serde(default)]
+    pub struct Cancel {
+        reason: Option<String>,
+        requester: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Move {
+        position: usize,
+    }
+
+    #[post("/hosts", format = "json", data = "<registration>")]
+    pub fn register(
+        auth: HostAuth,
+        registration: Json<Registration>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        let Registration {
+            hostname,
+            labels,
+            facts,
+        } = registration.into_inner();
+        match instance.register(&hostname, auth.enrolment(), labels, facts) {
+            Ok(id) => host::host(id, instance),
+            Err(err) => err!(err),
+        }
+    }
+
+    #[put("/hosts/<host>/state", format = "json", data = "<state>")]
+    pub fn status(
+        auth: HostAuth,
+        host: HostId,
+        state: Json<HostState>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        if let Err(err) = acting_as(&auth, &instance, host) {
+            return err!(err);
+        }
+        report_state(&instance, host, state.into_inner())
+            .map(|_| ok!())
+            .unwrap_or_else(|err| err)
+    }
+
+    /// Records a heartbeat from a host, along with any telemetry sampled with it, replying with
+    /// whether the host should abandon the invocation it is running.
+    #[put("/hosts/<host>/heartbeat", format = "json", data = "<heartbeat>")]
+    pub fn heartbeat(
+        auth: HostAuth,
+        host: HostId,
+        heartbeat: Json<Heartbeat>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        if let Err(err) = acting_as(&auth, &instance, host) {
+            return err!(err);
+        }
+        let heartbeat = heartbeat.into_inner();
+        let state = heartbeat.state();
+        if let Some(telemetry) = heartbeat.into_telemetry() {
+            instance.host(host, |host| host.record(telemetry));
+        }
+        report_state(&instance, host, state)
+            .and_then(|_| {
+                instance
+                    .host(host, |host| ok!(host.draining()))
+                    .ok_or_else(|| err!())
+            })
+            .unwrap_or_else(|err| err)
+    }
+
+    /// Records the state a host reports for itself, which can't be disconnected (that is only
+    /// ever decided by the server).
+    fn report_state(instance: &Instance, host: HostId, state: HostState) -> Result<(), JsonValue> {
+        match state {
+            HostState::Disconnected => Err(err!("hosts cannot report themselves disconnected")),
+            state => instance.set_state(host, state).ok_or_else(|| err!()),
+        }
+    }
+
+    /// The most recent telemetry samples sent by a host, oldest first.
+    #[get("/hosts/<host>/telemetry")]
+    pub fn telemetry(host: HostId, instance: State<Instance>) -> JsonValue {
+        instance
+            .host(host, |host| ok!(host.telemetry()))
+            .unwrap_or_else(|| err!())
+    }
+
+    /// Takes a host out of rotation. It finishes whatever it is running but takes part in no new
+    /// invocations until uncordoned.
+    #[put("/hosts/<host>/cordon")]
+    pub fn cordon(_admin: AdminAuth, host: HostId, instance: State<Instance>) -> JsonValue {
+        instance
+            .cordon(host, true)
+            .map(|_| ok!())
+            .unwrap_or_else(|| err!())
+    }
+
+    #[delete("/hosts/<host>/cordon")]
+    pub fn uncordon(_admin: AdminAuth, host: HostId, instance: State<Instance>) -> JsonValue {
+        instance
+            .cordon(host, false)
+            .map(|_| ok!())
+            .unwrap_or_else(|| err!())
+    }
+
+    /// Cordons a host and has it abandon the invocation it is running.
+    #[post("/hosts/<host>/drain")]
+    pub fn drain(_admin: AdminAuth, host: HostId, instance: State<Instance>) -> JsonValue {
+        instance
+            .drain(host)
+            .map(|_| ok!())
+            .unwrap_or_else(|| err!())
+    }
+
+    #[post("/invocations", format = "json", data = "<invoke>")]
+    pub fn invoke(user: UserAuth, invoke: Json<Invoke>, instance: State<Instance>) -> JsonValue {
+        let invoke = invoke.into_inner();
+        super::invoke(user, invoke.url, invoke.rev, instance)
+    }
+
+    #[post("/invocations/<id>/reinvoke")]
+    pub fn reinvoke(user: UserAuth, id: InvocationId, instance: State<Instance>) -> JsonValue {
+        super::reinvoke(user, id, instance)
+    }
+
+    /// Cancels the current invocation. The body, giving the reason and requester, is optional.
+    #[delete("/current", data = "<cancel>")]
+    pub fn cancel(
+        user: UserAuth,
+        cancel: Option<Json<Cancel>>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        let cancel = cancel.map(Json::into_inner).unwrap_or_default();
+        super::cancel(user, cancel.reason, cancel.requester, instance)
+    }
+
+    #[put("/queue/<id>/position", format = "json", data = "<position>")]
+    pub fn reorder(
+        user: UserAuth,
+        id: InvocationId,
+        position: Json<Move>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        super::reorder(user, id, position.position, instance)
+    }
+
+    /// Removes an invocation from the queue or, given the parent of a matrix, every child of it
+    /// still queued. The body, giving the reason and requester, is optional.
+    #[delete("/queue/<id>", data = "<cancel>")]
+    pub fn dequeue(
+        user: UserAuth,
+        id: InvocationId,
+        cancel: Option<Json<Cancel>>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        let cancel = cancel.map(Json::into_inner).unwrap_or_default();
+        super::dequeue(user, id, cancel.reason, cancel.requester, instance)
+    }
+
+    /// Holds the request open until the current invocation is something other than `since` (or
+    /// there is one, if `since` is missing), or until `timeout` seconds have passed, returning
+    /// whether it changed. Hosts can then fetch the new invocation as soon as it starts, rather
+    /// than polling for it.
+    #[get("/current/wait?<since>&<timeout>")]
+    pub fn wait(
+        _auth: HostAuth,
+        since: Option<InvocationId>,
+        timeout: Option<u64>,
+        waiters: State<Waiters>,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        let timeout = timeout
+            .map(time::Duration::from_secs)
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT);
+        match waiters.enter() {
+            Some(_waiter) => ok!(instance.wait_for_change(since, timeout)),
+            None => err!("too many requests are already waiting"),
+        }
+    }
+
+    #[post("/barrier/<id>/<name>/<host>")]
+    pub fn arrive(
+        auth: HostAuth,
+        id: InvocationId,
+        name: String,
+        host: HostId,
+        instance: State<Instance>,
+    ) -> JsonValue {
+        super::arrive(auth, id, name, host, instance)
+    }
+}
+
 #[get("/")]
 fn index() -> Template {
     Template::render("index", ())
 }
 
+/// Exposes metrics about hosts, invocations and uploads in the Prometheus text format.
+#[get("/metrics")]
+fn metrics(instance: State<Instance>) -> Content<String> {
+    Content(ContentType::Plain, metrics::render(&instance))
+}
+
 #[get("/hosts")]
 fn hosts(instance: State<Instance>) -> JsonValue {
     instance.hosts(|iter| ok!(iter.collect::<Vec<_>>()))
@@ -189,9 +429,84 @@ fn invocations(instance: State<Instance>) -> JsonValue {
     })
 }
 
-#[get("/invoke/<url>")]
-fn invoke(url: String, instance: State<Instance>) -> JsonValue {
-    match instance.invoke(&url) {
+/// Streams changes to hosts and invocations as server-sent events, as they happen. Each
+/// subscription ends after `MAX_WAIT`, and is turned away if too many requests are held open.
+#[get("/events")]
+fn events<'r>(
+    waiters: State<'r, Waiters>,
+    instance: State<Instance>,
+) -> Result<Subscription<'r>, Status> {
+    match waiters.inner().enter() {
+        Some(waiter) => Ok(instance.subscribe(waiter)),
+        None => Err(Status::ServiceUnavailable),
+    }
+}
+
+#[get("/queue")]
+fn queue(instance: State<Instance>) -> JsonValue {
+    ok!(instance.queued())
+}
+
+#[post("/queue/<id>/move/<position>")]
+fn reorder(
+    _user: UserAuth,
+    id: InvocationId,
+    position: usize,
+    instance: State<Instance>,
+) -> JsonValue {
+    match instance.reorder(id, position) {
+        Ok(_) => ok!(instance.queued()),
+        Err(err) => err!(err),
+    }
+}
+
+#[post("/queue/<id>/remove?<reason>&<requester>")]
+fn dequeue(
+    user: UserAuth,
+    id: InvocationId,
+    reason: Option<String>,
+    requester: Option<String>,
+    instance: State<Instance>,
+) -> JsonValue {
+    let requester = requester_of(&user, requester);
+    match instance.dequeue(id, Cancellation::new(reason, requester)) {
+        Ok(_) => ok!(instance.queued()),
+        Err(err) => err!(err),
+    }
+}
+
+#[get("/barrier/<id>/<name>")]
+fn barrier(id: InvocationId, name: String, instance: State<Instance>) -> JsonValue {
+    instance
+        .barrier(id, &name, |barrier| ok!(barrier))
+        .unwrap_or_else(|| err!())
+}
+
+#[post("/barrier/<id>/<name>/<host>")]
+fn arrive(
+    auth: HostAuth,
+    id: InvocationId,
+    name: String,
+    host: HostId,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        return err!(err);
+    }
+    match instance.arrive(id, &name, host) {
+        Ok(released) => ok!(released),
+        Err(err) => err!(err),
+    }
+}
+
+#[get("/invoke/<url>?<rev>")]
+fn invoke(
+    _user: UserAuth,
+    url: String,
+    rev: Option<String>,
+    instance: State<Instance>,
+) -> JsonValue {
+    match instance.invoke(&url, rev.as_ref().map(String::as_str)) {
         Ok(id) => instance
             .invocation(id, |invocation| ok!(invocation))
             .unwrap_or_else(|| err!()),
@@ -200,7 +515,7 @@ fn invoke(url: String, instance: State<Instance>) -> JsonValue {
 }
 
 #[get("/reinvoke/<id>")]
-fn reinvoke(id: InvocationId, instance: State<Instance>) -> JsonValue {
+fn reinvoke(_user: UserAuth, id: InvocationId, instance: State<Instance>) -> JsonValue {
     match instance.reinvoke(id) {
         Ok(id) => instance
             .invocation(id, |invocation| ok!(invocation))
@@ -209,29 +524,322 @@ fn reinvoke(id: InvocationId, instance: State<Instance>) -> JsonValue {
     }
 }
 
-#[get("/cancel")]
-fn cancel(instance: State<Instance>) -> JsonValue {
-    instance.cancel();
+#[get("/cancel?<reason>&<requester>")]
+fn cancel(
+    user: UserAuth,
+    reason: Option<String>,
+    requester: Option<String>,
+    instance: State<Instance>,
+) -> JsonValue {
+    let requester = requester_of(&user, requester);
+    instance.cancel(Cancellation::new(reason, requester));
     ok!()
 }
 
 #[post("/upload/<id>/<host>", data = "<upload>")]
 fn upload(
-    upload: LogUpload,
+    auth: HostAuth,
+    upload: Result<LogUpload, UploadError>,
+    id: InvocationId,
+    host: HostId,
+    instance: State<Instance>,
+) -> JsonValue {
+    let upload = match upload {
+        Ok(upload) => upload,
+        Err(err) => return err!(err),
+    };
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        fs::remove_file(&upload.0).unwrap_or(());
+        return err!(err);
+    }
+    match instance.add_log(id, host, &upload.0) {
+        Ok(_) => ok!(),
+        Err(err) => {
+            fs::remove_file(&upload.0).unwrap_or(());
+            err!(err)
+        }
+    }
+}
+
+/// Starts a chunked upload of a log archive of the given size and hex-encoded SHA-256 digest,
+/// which the client then sends with `upload_chunk`, resuming from `upload_status` if interrupted.
+#[post("/uploads/<id>/<host>?<size>&<checksum>")]
+fn begin_upload(
+    auth: HostAuth,
+    id: InvocationId,
+    host: HostId,
+    size: u64,
+    checksum: String,
+    limit: UploadLimit,
+    uploads: State<Uploads>,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        return err!(err);
+    }
+    if instance.invocation(id, |_| ()).is_none() || instance.host(host, |_| ()).is_none() {
+        return err!("the supplied invocation or host ID was invalid");
+    }
+    match uploads.begin(id, host, size, &checksum, limit.0) {
+        Ok(status) => ok!(status),
+        Err(err) => err!(err),
+    }
+}
+
+#[get("/uploads/<upload>")]
+fn upload_status(
+    auth: HostAuth,
+    upload: UploadId,
+    uploads: State<Uploads>,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Some(Err(err)) = uploads
+        .host(upload)
+        .map(|host| acting_as(&auth, &instance, host))
+    {
+        return err!(err);
+    }
+    match uploads.status(upload) {
+        Ok(status) => ok!(status),
+        Err(err) => err!(err),
+    }
+}
+
+#[post("/uploads/<upload>?<offset>", data = "<chunk>")]
+fn upload_chunk(
+    auth: HostAuth,
+    upload: UploadId,
+    offset: u64,
+    chunk: Data,
+    uploads: State<Uploads>,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Some(Err(err)) = uploads
+        .host(upload)
+        .map(|host| acting_as(&auth, &instance, host))
+    {
+        return err!(err);
+    }
+    let chunk = chunk.open().take(upload::MAX_CHUNK);
+    let result = uploads.append(
+        upload,
+        offset,
+        chunk,
+        instance.metrics(),
+        |id, host, path| {
+            instance
+                .add_log(id, host, path)
+                .map_err(|err| Box::new(err) as Box<dyn Error>)
+        },
+    );
+    match result {
+        Ok(status) => ok!(status),
+        Err(err) => err!(err),
+    }
+}
+
+#[post("/stream/<id>/<host>/<name..>?<offset>", data = "<chunk>")]
+fn stream(
+    auth: HostAuth,
+    id: InvocationId,
+    host: HostId,
+    name: PathBuf,
+    offset: u64,
+    chunk: Data,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        return err!(err);
+    }
+    let hostname = match instance.host(host, |host| host.hostname().to_string()) {
+        Some(hostname) => hostname,
+        None => return err!("no such host"),
+    };
+    if !participating(&instance, id, &hostname) {
+        return err!("host is not taking part in the invocation");
+    }
+    let path = stream::dir(LOG_DIR, id, &hostname).join(name);
+    match stream::append(path, offset, chunk.open().take(stream::MAX_CHUNK)) {
+        Ok(length) => ok!(length),
+        Err(err) => err!(err),
+    }
+}
+
+#[get("/invocation/<id>/streams/<hostname>")]
+fn streams(id: InvocationId, hostname: String, instance: State<Instance>) -> JsonValue {
+    if !participating(&instance, id, &hostname) {
+        return err!("host is not taking part in the invocation");
+    }
+    match stream::list(stream::dir(LOG_DIR, id, &hostname)) {
+        Ok(entries) => ok!(entries),
+        Err(err) => err!(err),
+    }
+}
+
+/// Reads a stream from the given offset. If `follow` is set, the response is held open and
+/// further data sent as it arrives, until the host finishes with the invocation or `MAX_WAIT` has
+/// passed. The reader carries on by requesting again from the offset it has reached (the given
+/// offset plus what it received), until the invocation lists logs from the host.
+#[get("/invocation/<id>/streams/<hostname>/<name..>?<offset>&<follow>")]
+fn read_stream<'r>(
+    id: InvocationId,
+    hostname: String,
+    name: PathBuf,
+    offset: Option<u64>,
+    follow: Option<bool>,
+    waiters: State<'r, Waiters>,
+    instance: State<'r, Instance>,
+) -> Result<Stream<Box<dyn Read + 'r>>, Custom<String>> {
+    if !participating(&instance, id, &hostname) {
+        return Err(Custom(
+            Status::NotFound,
+            "host is not taking part in the invocation".to_string(),
+        ));
+    }
+    let path = stream::dir(LOG_DIR, id, &hostname).join(name);
+    let offset = offset.unwrap_or(0);
+    let reader: io::Result<Box<dyn Read + 'r>> = if follow.unwrap_or(false) {
+        let waiter = waiters.inner().enter().ok_or_else(|| {
+            Custom(
+                Status::ServiceUnavailable,
+                "too many requests are already waiting".to_string(),
+            )
+        })?;
+        Follow::open(&path, offset, instance.inner(), id, &hostname, waiter)
+            .map(|follow| Box::new(follow) as Box<dyn Read + 'r>)
+    } else {
+        File::open(&path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(Box::new(file) as Box<dyn Read + 'r>)
+        })
+    };
+    reader
+        .map(Stream::from)
+        .map_err(|err| Custom(Status::NotFound, format!("{}", err)))
+}
+
+#[post("/report/<id>/<host>", format = "json", data = "<report>")]
+fn report(
+    auth: HostAuth,
+    report: Json<HostReport>,
     id: InvocationId,
     host: HostId,
     instance: State<Instance>,
 ) -> JsonValue {
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        return err!(err);
+    }
+    match instance.report(id, host, report.into_inner()) {
+        Ok(_) => ok!(),
+        Err(err) => err!(err),
+    }
+}
+
+#[post("/report/<id>/<host>/phase", format = "json", data = "<report>")]
+fn report_phase(
+    auth: HostAuth,
+    report: Json<PhaseReport>,
+    id: InvocationId,
+    host: HostId,
+    instance: State<Instance>,
+) -> JsonValue {
+    if let Err(err) = acting_as(&auth, &instance, host) {
+        return err!(err);
+    }
+    match instance.report_phase(id, host, report.into_inner()) {
+        Ok(_) => ok!(),
+        Err(err) => err!(err),
+    }
+}
+
+/// Lists the files in the log archive uploaded by the named host.
+#[get("/invocation/<id>/logs/<hostname>")]
+fn archive(id: InvocationId, hostname: String, instance: State<Instance>) -> JsonValue {
+    match log(&instance, id, &hostname) {
+        Some(path) => match archive::entries(path) {
+            Ok(entries) => ok!(entries),
+            Err(err) => err!(err),
+        },
+        None => err!("host has not uploaded logs for the invocation"),
+    }
+}
+
+/// Reads a single file out of the log archive uploaded by the named host, honouring any `Range`
+/// header.
+#[get("/invocation/<id>/logs/<hostname>/<name..>")]
+fn archive_file(
+    id: InvocationId,
+    hostname: String,
+    name: PathBuf,
+    range: Range,
+    instance: State<Instance>,
+) -> Result<Slice, SliceError> {
+    match log(&instance, id, &hostname) {
+        Some(path) => archive::open(path, name, &range),
+        None => Err(SliceError::NotFound),
+    }
+}
+
+/// Downloads the logs of every host that has uploaded them as a single archive, with a directory
+/// for each host and a manifest describing the invocation.
+#[get("/invocation/<id>/logs.tar.gz")]
+fn merged_logs(id: InvocationId, instance: State<Instance>) -> Result<Merged, SliceError> {
     instance
         .invocation(id, |invocation| {
-            instance
-                .host(host, |host| {
-                    invocation.add_log(host, upload.0);
-                    json!({ "status": "ok" })
-                })
-                .unwrap_or_else(|| err!())
+            let mut logs = invocation
+                .logs()
+                .iter()
+                .map(|(hostname, path)| (hostname.clone(), path.clone()))
+                .collect::<Vec<_>>();
+            logs.sort();
+            let manifest = serde_json::to_vec_pretty(invocation).unwrap_or_default();
+            (logs, manifest)
+        })
+        .ok_or(SliceError::NotFound)
+        .and_then(|(logs, manifest)| {
+            archive::merge(format!("{}.tar.gz", id), manifest, logs).map_err(SliceError::from)
         })
-        .unwrap_or_else(|| err!())
+}
+
+/// The path of the log archive uploaded by the named host for the given invocation.
+fn log(instance: &Instance, id: InvocationId, hostname: &str) -> Option<PathBuf> {
+    instance
+        .invocation(id, |invocation| {
+            invocation.log(hostname).map(Path::to_path_buf)
+        })
+        .and_then(|path| path)
+}
+
+/// Who requested a cancellation. Authenticated users are recorded as the requester, whoever they
+/// claim to be.
+fn requester_of(user: &UserAuth, claimed: Option<String>) -> Option<String> {
+    user.name().map(str::to_string).or(claimed)
+}
+
+/// Checks that a request made with the given host token may act as the given host, which it only
+/// may with the token the host registered with. Unknown hosts are left for the route to reject.
+fn acting_as(auth: &HostAuth, instance: &Instance, host: HostId) -> Result<(), InstanceError> {
+    match instance.host(host, |host| auth.is(host)) {
+        Some(false) => Err(InstanceErrorKind::WrongHost.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the named host is taking part in the given invocation.
+fn participating(instance: &Instance, id: InvocationId, hostname: &str) -> bool {
+    instance
+        .invocation(id, |invocation| {
+            invocation
+                .participants()
+                .iter()
+                .any(|participant| participant == hostname)
+        })
+        .unwrap_or(false)
+}
+
+#[catch(401)]
+fn unauthorized(_request: &Request) -> JsonValue {
+    err!("a valid token is required")
 }
 
 #[catch(404)]
@@ -244,14 +852,45 @@ fn internal_error(_request: &Request) -> JsonValue {
     err!("internal server error")
 }
 
+/// Creates an empty directory of its own for a test to keep files in.
+#[cfg(test)]
+fn scratch() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("cluster-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
 fn main() {
     fs::create_dir(LOG_DIR).unwrap_or(());
     rocket::ignite()
-        .manage(Instance::new("experiment/"))
-        .register(catchers![internal_error, not_found])
+        .attach(AdHoc::on_attach("Instance", |rocket| {
+            let data_dir = rocket
+                .config()
+                .get_str("data_dir")
+                .unwrap_or(DATA_DIR_DEFAULT)
+                .to_string();
+            match Instance::new(EXPERIMENT_DIR, &data_dir) {
+                Ok(instance) => Ok(rocket.manage(instance)),
+                Err(err) => {
+                    error!("failed to open journal in {}: {}", data_dir, err);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Tokens", |rocket| {
+            let tokens = Tokens::from_config(rocket.config());
+            Ok(rocket.manage(tokens))
+        }))
+        .attach(AdHoc::on_attach("Waiters", |rocket| {
+            let waiters = Waiters::from_config(rocket.config());
+            Ok(rocket.manage(waiters))
+        }))
+        .manage(Uploads::new(LOG_DIR))
+        .register(catchers![internal_error, not_found, unauthorized])
         .mount("/static", StaticFiles::from("static/"))
         .mount("/logs", StaticFiles::from("logs/"))
-        .mount("/", routes![index])
+        .mount("/", routes![index, metrics])
+        // Version 1 of the API, kept for older clients
         .mount(
             "/api",
             routes![
@@ -259,10 +898,27 @@ fn main() {
                 current,
                 invocation,
                 invocations,
+                events,
+                queue,
+                reorder,
+                dequeue,
+                barrier,
+                arrive,
                 invoke,
                 reinvoke,
                 cancel,
-                upload
+                upload,
+                begin_upload,
+                upload_status,
+                upload_chunk,
+                stream,
+                streams,
+                read_stream,
+                archive,
+                archive_file,
+                merged_logs,
+                report,
+                report_phase
             ],
         )
         .mount("/api/host", routes![host::host, host::register])
@@ -277,6 +933,44 @@ fn main() {
                 host::status::done
             ],
         )
+        .mount(
+            "/api/v2",
+            routes![
+                hosts,
+                current,
+                invocation,
+                invocations,
+                events,
+                queue,
+                barrier,
+                begin_upload,
+                upload_status,
+                upload_chunk,
+                stream,
+                streams,
+                read_stream,
+                archive,
+                archive_file,
+                merged_logs,
+                report,
+                report_phase,
+                v2::register,
+                v2::status,
+                v2::heartbeat,
+                v2::telemetry,
+                v2::cordon,
+                v2::uncordon,
+                v2::drain,
+                v2::invoke,
+                v2::reinvoke,
+                v2::cancel,
+                v2::reorder,
+                v2::dequeue,
+                v2::wait,
+                v2::arrive
+            ],
+        )
+        .mount("/api/v2/hosts", routes![host::host])
         .attach(Template::fairing())
         .launch();
 }
@@ -0,0 +1,221 @@
+use crate::instance::Instance;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+/// The upper bounds, in seconds, of the buckets for upload durations.
+const UPLOAD_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+/// The upper bounds, in seconds, of the buckets for clone durations.
+const CLONE_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Measurements taken as the server runs, as opposed to those read off the state of the instance
+/// whenever metrics are gathered.
+pub struct Metrics {
+    upload_bytes: AtomicU64,
+    uploads: Histogram,
+    clones: Histogram,
+}
+
+/// Counts observations into buckets by their upper bounds, as Prometheus expects.
+struct Histogram {
+    bounds: &'static [f64],
+    inner: Mutex<Observations>,
+}
+
+#[derive(Default)]
+struct Observations {
+    /// How many observations fell into each bucket (and no lower one).
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            upload_bytes: AtomicU64::new(0),
+            uploads: Histogram::new(UPLOAD_BUCKETS),
+            clones: Histogram::new(CLONE_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a log archive of the given size, in bytes, having been uploaded in full.
+    pub fn record_upload(&self, size: u64, duration: time::Duration) {
+        self.upload_bytes.fetch_add(size, Ordering::Relaxed);
+        self.uploads.observe(duration);
+    }
+
+    /// Records a repository having been cloned (or fetched).
+    pub fn record_clone(&self, duration: time::Duration) {
+        self.clones.observe(duration);
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            inner: Mutex::new(Observations {
+                counts: vec![0; bounds.len()],
+                ..Observations::default()
+            }),
+        }
+    }
+
+    fn observe(&self, duration: time::Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            inner.counts[bucket] += 1;
+        }
+        inner.sum += seconds;
+        inner.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(inner.counts.iter()) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative)?;
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, inner.count)?;
+        writeln!(out, "{}_sum {}", name, inner.sum)?;
+        writeln!(out, "{}_count {}", name, inner.count)
+    }
+}
+
+/// Gathers every metric in the Prometheus text format.
+pub fn render(instance: &Instance) -> String {
+    let mut out = String::new();
+    write(&mut out, instance).expect("writing to a string cannot fail");
+    out
+}
+
+fn write(out: &mut String, instance: &Instance) -> fmt::Result {
+    let (states, heartbeats) = instance.hosts(|iter| {
+        let mut states = BTreeMap::<String, u64>::new();
+        let mut heartbeats = vec![];
+        for host in iter {
+            *states.entry(label(&host.state())).or_insert(0) += 1;
+            heartbeats.push((host.hostname().to_string(), host.since_heartbeat()));
+        }
+        (states, heartbeats)
+    });
+    let outcomes = instance.invocations(|iter| {
+        let mut outcomes = BTreeMap::<String, u64>::new();
+        for invocation in iter {
+            let outcome = match invocation.outcome() {
+                Some(outcome) => label(&outcome),
+                None => "pending".to_string(),
+            };
+            *outcomes.entry(outcome).or_insert(0) += 1;
+        }
+        outcomes
+    });
+    let metrics = instance.metrics();
+
+    writeln!(out, "# HELP cluster_hosts Registered hosts, by state.")?;
+    writeln!(out, "# TYPE cluster_hosts gauge")?;
+    for (state, count) in states.iter() {
+        writeln!(
+            out,
+            "cluster_hosts{{state=\"{}\"}} {}",
+            escape(state),
+            count
+        )?;
+    }
+
+    writeln!(
+        out,
+        "# HELP cluster_host_heartbeat_age_seconds Time since each host was last heard from."
+    )?;
+    writeln!(out, "# TYPE cluster_host_heartbeat_age_seconds gauge")?;
+    for (hostname, age) in heartbeats.iter() {
+        writeln!(
+            out,
+            "cluster_host_heartbeat_age_seconds{{hostname=\"{}\"}} {}",
+            escape(hostname),
+            age.as_secs() as f64 + f64::from(age.subsec_millis()) / 1e3
+        )?;
+    }
+
+    writeln!(out, "# HELP cluster_invocations_total Invocations created.")?;
+    writeln!(out, "# TYPE cluster_invocations_total counter")?;
+    writeln!(
+        out,
+        "cluster_invocations_total {}",
+        outcomes.values().sum::<u64>()
+    )?;
+
+    writeln!(out, "# HELP cluster_invocations Invocations, by outcome.")?;
+    writeln!(out, "# TYPE cluster_invocations gauge")?;
+    for (outcome, count) in outcomes.iter() {
+        writeln!(
+            out,
+            "cluster_invocations{{outcome=\"{}\"}} {}",
+            escape(outcome),
+            count
+        )?;
+    }
+
+    writeln!(
+        out,
+        "# HELP cluster_queue_length Invocations waiting to run."
+    )?;
+    writeln!(out, "# TYPE cluster_queue_length gauge")?;
+    writeln!(out, "cluster_queue_length {}", instance.queued().len())?;
+
+    writeln!(
+        out,
+        "# HELP cluster_upload_bytes_total Bytes of log archives received."
+    )?;
+    writeln!(out, "# TYPE cluster_upload_bytes_total counter")?;
+    writeln!(
+        out,
+        "cluster_upload_bytes_total {}",
+        metrics.upload_bytes.load(Ordering::Relaxed)
+    )?;
+
+    metrics.uploads.write(
+        out,
+        "cluster_upload_duration_seconds",
+        "Time taken to receive each log archive.",
+    )?;
+    metrics.clones.write(
+        out,
+        "cluster_clone_duration_seconds",
+        "Time taken to clone (or fetch) each experiment repository.",
+    )
+}
+
+/// The name a value is serialised under (or, for internally tagged enums, the name of its
+/// variant), for use as a label.
+fn label<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(name)) => name,
+        Ok(Value::Object(ref fields)) => match fields.get("desc") {
+            Some(Value::String(name)) => name.clone(),
+            _ => "unknown".to_string(),
+        },
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,171 @@
+use cluster::invocation::InvocationId;
+
+use crate::instance::Instance;
+use crate::wait::{Waiter, MAX_WAIT};
+
+use serde::Serialize;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::{thread, time};
+
+/// The directory, within the log directory, in which streamed logs are stored.
+const STREAM_DIR: &str = "streams/";
+/// The largest chunk a host may send at once, in bytes.
+pub const MAX_CHUNK: u64 = 1 << 20;
+/// How often a followed stream is checked for new data.
+const FOLLOW_INTERVAL: time::Duration = time::Duration::from_millis(250);
+/// How long a followed stream is kept open after its invocation finishes, in case the host still
+/// has data in flight.
+const FOLLOW_LINGER: time::Duration = time::Duration::from_secs(5);
+
+/// A file streamed by a host, along with how much of it has been received so far.
+#[derive(Serialize)]
+pub struct StreamEntry {
+    name: PathBuf,
+    size: u64,
+}
+
+/// The directory in which the streams from the given host for the given invocation are stored.
+pub fn dir<P: AsRef<Path>>(log_dir: P, id: InvocationId, hostname: &str) -> PathBuf {
+    log_dir
+        .as_ref()
+        .join(STREAM_DIR)
+        .join(format!("{}", id))
+        .join(hostname)
+}
+
+/// Appends a chunk, which the host sent from the given offset, to a stream, returning the length
+/// of the stream afterwards. Any part of the chunk the server already has (as when the host
+/// retries a chunk whose response was lost) is skipped.
+pub fn append<P: AsRef<Path>, R: Read>(path: P, offset: u64, chunk: R) -> io::Result<u64> {
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(&path)?;
+    let length = file.metadata()?.len();
+    if offset > length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "chunk at offset {} would leave a gap after {}",
+                offset, length
+            ),
+        ));
+    }
+    let mut chunk = chunk;
+    io::copy(&mut chunk.by_ref().take(length - offset), &mut io::sink())?;
+    io::copy(&mut chunk, &mut file)?;
+    Ok(file.metadata()?.len())
+}
+
+/// Every stream in the given directory, sorted by name.
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<StreamEntry>> {
+    let mut entries = vec![];
+    if dir.as_ref().exists() {
+        walk(dir.as_ref(), dir.as_ref(), &mut entries)?;
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<StreamEntry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(root, &entry.path(), entries)?;
+        } else if let Ok(name) = entry.path().strip_prefix(root) {
+            entries.push(StreamEntry {
+                name: name.to_path_buf(),
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads a stream from the given offset and, once the end is reached, waits for more data until
+/// the host has finished with the invocation or `MAX_WAIT` has passed, whichever comes first.
+/// Each batch of data is followed by a read of nothing, so that Rocket sends it as a chunk of its
+/// own rather than holding it back until its buffer fills.
+pub struct Follow<'r> {
+    file: File,
+    instance: &'r Instance,
+    id: InvocationId,
+    hostname: String,
+    finished: Option<time::Instant>,
+    deadline: time::Instant,
+    /// Whether data has been read since the last chunk was ended.
+    sent: bool,
+    _waiter: Waiter<'r>,
+}
+
+impl<'r> Follow<'r> {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        offset: u64,
+        instance: &'r Instance,
+        id: InvocationId,
+        hostname: &str,
+        waiter: Waiter<'r>,
+    ) -> io::Result<Follow<'r>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Follow {
+            file,
+            instance,
+            id,
+            hostname: hostname.to_string(),
+            finished: None,
+            deadline: time::Instant::now() + MAX_WAIT,
+            sent: false,
+            _waiter: waiter,
+        })
+    }
+
+    /// Whether the host will send no more data, either because it has uploaded its logs or the
+    /// invocation finished a while ago.
+    fn done(&mut self) -> bool {
+        let hostname = &self.hostname;
+        let (logged, finished) = self
+            .instance
+            .invocation(self.id, |invocation| {
+                (
+                    invocation.host_has_logged(hostname),
+                    invocation.outcome().is_some(),
+                )
+            })
+            .unwrap_or((true, true));
+        if logged {
+            return true;
+        }
+        if finished {
+            let since = *self.finished.get_or_insert_with(time::Instant::now);
+            return since.elapsed() >= FOLLOW_LINGER;
+        }
+        false
+    }
+}
+
+impl<'r> Read for Follow<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.sent |= read > 0;
+                return Ok(read);
+            }
+            if self.sent {
+                self.sent = false;
+                return Ok(0);
+            }
+            if self.done() || time::Instant::now() >= self.deadline {
+                // Catch anything written between the last read and the host finishing
+                return self.file.read(buf);
+            }
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use cluster::host::HostId;
+use cluster::invocation::InvocationId;
+use cluster::upload::{UploadId, UploadStatus};
+
+use multipart::server::Multipart;
+
+use rocket::data::{self, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Outcome, Request, State};
+
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time;
+
+use uuid::Uuid;
+
+use crate::instance::Instance;
+use crate::metrics::Metrics;
+use crate::stream;
+use crate::LOG_DIR;
+
+/// The name of the limit, in Rocket's `limits` configuration, on the size of uploaded logs.
+const LIMIT: &str = "logs";
+/// How large uploaded logs may be, in bytes, unless `limits.logs` is set in Rocket's
+/// configuration.
+const LIMIT_DEFAULT: u64 = 4 << 30;
+/// The largest chunk of a chunked upload a host may send at once, in bytes.
+pub const MAX_CHUNK: u64 = 1 << 20;
+/// How long a chunked upload may go without a chunk before it is abandoned, along with whatever
+/// was received of it.
+const SESSION_TTL: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// A log archive that has been received in full and written to the log directory.
+#[derive(Debug)]
+pub struct LogUpload(pub PathBuf);
+
+/// The maximum size of uploaded logs, in bytes, as a request guard.
+pub struct UploadLimit(pub u64);
+
+/// Chunked uploads that have been started but not yet completed. Sessions are kept in memory
+/// only, so clients start afresh if the server restarts mid-upload. Sessions that go
+/// `SESSION_TTL` without a chunk expire, and are reaped (along with their partial files, and any
+/// left behind by a restart) as uploads begin and chunks arrive.
+pub struct Uploads {
+    /// Where partial files are kept, and completed archives moved to.
+    dir: PathBuf,
+    sessions: Mutex<HashMap<UploadId, Session>>,
+}
+
+/// A chunked upload of a log archive, received into a partial file.
+struct Session {
+    invocation: InvocationId,
+    host: HostId,
+    size: u64,
+    checksum: String,
+    started: time::Instant,
+    /// When the session was started or last sent a chunk.
+    touched: time::Instant,
+}
+
+#[derive(Debug)]
+pub struct UploadError {
+    cause: Option<Box<dyn Error>>,
+    kind: UploadErrorKind,
+}
+
+#[derive(Debug)]
+pub enum UploadErrorKind {
+    /// The request was not a multipart form containing a log archive.
+    BadRequest,
+    /// The log archive was larger than the given maximum size, in bytes.
+    TooLarge(u64),
+    /// The SHA-256 digest of the log archive did not match the one supplied by the client.
+    ChecksumMismatch,
+    /// The log archive couldn't be written to disk.
+    WriteFailed,
+    /// The supplied upload session ID was invalid (or the session has expired).
+    InvalidSession,
+}
+
+impl fmt::Display for UploadErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadErrorKind::BadRequest => write!(
+                f,
+                "the request was not a multipart form containing a log archive"
+            ),
+            UploadErrorKind::TooLarge(limit) => write!(
+                f,
+                "the log archive was larger than the maximum size of {} bytes",
+                limit
+            ),
+            UploadErrorKind::ChecksumMismatch => write!(
+                f,
+                "the SHA-256 digest of the log archive did not match the one supplied"
+            ),
+            UploadErrorKind::WriteFailed => {
+                write!(f, "the log archive couldn't be written to disk")
+            }
+            UploadErrorKind::InvalidSession => {
+                write!(f, "the supplied upload session ID was invalid")
+            }
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Error for UploadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.cause {
+            Some(ref cause) => Some(&**cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<UploadErrorKind> for UploadError {
+    fn from(kind: UploadErrorKind) -> UploadError {
+        UploadError { cause: None, kind }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(err: io::Error) -> UploadError {
+        UploadError {
+            cause: Some(Box::new(err)),
+            kind: UploadErrorKind::WriteFailed,
+        }
+    }
+}
+
+impl UploadError {
+    fn status(&self) -> Status {
+        match self.kind {
+            UploadErrorKind::BadRequest => Status::BadRequest,
+            UploadErrorKind::TooLarge(_) => Status::PayloadTooLarge,
+            UploadErrorKind::ChecksumMismatch => Status::UnprocessableEntity,
+            UploadErrorKind::WriteFailed => Status::InternalServerError,
+            UploadErrorKind::InvalidSession => Status::NotFound,
+        }
+    }
+}
+
+impl FromDataSimple for LogUpload {
+    type Error = UploadError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let started = time::Instant::now();
+        let boundary = request
+            .headers()
+            .get_one("Content-Type")
+            .and_then(|content_type| {
+                content_type
+                    .find("boundary=")
+                    .map(|index| &content_type[(index + "boundary=".len())..])
+            });
+        let boundary = match boundary {
+            Some(boundary) => boundary,
+            None => {
+                let err = UploadError::from(UploadErrorKind::BadRequest);
+                return Outcome::Failure((err.status(), err));
+            }
+        };
+        let limit = limit(request);
+        let checksum = request.headers().get_one(cluster::CHECKSUM_HEADER);
+        // Allow for the multipart framing around the archive itself
+        let mut multipart = Multipart::with_body(data.open().take(limit + (1 << 20)), boundary);
+        let result = loop {
+            match multipart.read_entry() {
+                Ok(Some(mut entry)) => {
+                    if &*entry.headers.name == "log" {
+                        let path = Path::new(LOG_DIR)
+                            .join(&format!("{}", Uuid::new_v4()))
+                            .with_extension("tar.gz");
+                        break receive(&mut entry.data, &path, limit, checksum)
+                            .map(|_| LogUpload(path));
+                    }
+                }
+                Ok(None) => break Err(UploadErrorKind::BadRequest.into()),
+                Err(err) => {
+                    break Err(UploadError {
+                        cause: Some(Box::new(err)),
+                        kind: UploadErrorKind::BadRequest,
+                    })
+                }
+            }
+        };
+        match result {
+            Ok(upload) => {
+                if let (Outcome::Success(instance), Ok(metadata)) =
+                    (request.guard::<State<Instance>>(), fs::metadata(&upload.0))
+                {
+                    instance
+                        .metrics()
+                        .record_upload(metadata.len(), started.elapsed());
+                }
+                Outcome::Success(upload)
+            }
+            Err(err) => {
+                warn!("rejected log upload: {}", err);
+                Outcome::Failure((err.status(), err))
+            }
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UploadLimit {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<UploadLimit, ()> {
+        Outcome::Success(UploadLimit(limit(request)))
+    }
+}
+
+impl Uploads {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Uploads {
+        Uploads {
+            dir: dir.as_ref().to_path_buf(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a chunked upload of an archive of the given size and hex-encoded SHA-256 digest.
+    pub fn begin(
+        &self,
+        invocation: InvocationId,
+        host: HostId,
+        size: u64,
+        checksum: &str,
+        limit: u64,
+    ) -> Result<UploadStatus, UploadError> {
+        if size > limit {
+            return Err(UploadErrorKind::TooLarge(limit).into());
+        }
+        self.reap();
+        self.sweep();
+        let id = UploadId::generate();
+        File::create(self.partial(id))?;
+        let now = time::Instant::now();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Session {
+                invocation,
+                host,
+                size,
+                checksum: checksum.to_string(),
+                started: now,
+                touched: now,
+            },
+        );
+        Ok(UploadStatus::new(id, 0, size))
+    }
+
+    /// The host sending the given upload, if it is still in progress.
+    pub fn host(&self, id: UploadId) -> Option<HostId> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|session| session.host)
+    }
+
+    /// How much of the archive has been received so far.
+    pub fn status(&self, id: UploadId) -> Result<UploadStatus, UploadError> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get(&id)
+            .ok_or_else(|| UploadError::from(UploadErrorKind::InvalidSession))?;
+        let offset = fs::metadata(self.partial(id))?.len();
+        Ok(UploadStatus::new(id, offset, session.size))
+    }
+
+    /// Appends a chunk, sent from the given offset, to the upload. Once the whole archive has
+    /// arrived, it is checked against its digest and `complete` is called with the invocation and
+    /// host it belongs to and its final path, and the upload is recorded in `metrics`. If the check
+    /// fails, the session is abandoned and the client must start again.
+    pub fn append<R, F>(
+        &self,
+        id: UploadId,
+        offset: u64,
+        chunk: R,
+        metrics: &Metrics,
+        complete: F,
+    ) -> Result<UploadStatus, UploadError>
+    where
+        R: Read,
+        F: FnOnce(InvocationId, HostId, &Path) -> Result<(), Box<dyn Error>>,
+    {
+        self.reap();
+        let size = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .map(|session| {
+                session.touched = time::Instant::now();
+                session.size
+            })
+            .ok_or_else(|| UploadError::from(UploadErrorKind::InvalidSession))?;
+        let remaining = size.saturating_sub(offset);
+        let received = stream::append(self.partial(id), offset, chunk.take(remaining))?;
+        if received < size {
+            return Ok(UploadStatus::new(id, received, size));
+        }
+        // Whichever request completes the upload first takes the session
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| UploadError::from(UploadErrorKind::InvalidSession))?;
+        let path = self
+            .dir
+            .join(&format!("{}", Uuid::new_v4()))
+            .with_extension("tar.gz");
+        let result = File::open(self.partial(id))
+            .and_then(|mut file| digest(&mut file))
+            .map_err(UploadError::from)
+            .and_then(|digest| {
+                if session.checksum.trim().eq_ignore_ascii_case(&digest) {
+                    fs::rename(self.partial(id), &path).map_err(UploadError::from)
+                } else {
+                    Err(UploadErrorKind::ChecksumMismatch.into())
+                }
+            })
+            .and_then(|_| {
+                complete(session.invocation, session.host, &path).map_err(|err| UploadError {
+                    cause: Some(err),
+                    kind: UploadErrorKind::WriteFailed,
+                })
+            });
+        match result {
+            Ok(_) => metrics.record_upload(size, session.started.elapsed()),
+            Err(_) => {
+                fs::remove_file(self.partial(id)).unwrap_or(());
+                fs::remove_file(&path).unwrap_or(());
+            }
+        }
+        result.map(|_| UploadStatus::completed(id, size))
+    }
+
+    /// Abandons every session that has gone `SESSION_TTL` without a chunk.
+    fn reap(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let expired = sessions
+            .iter()
+            .filter(|(_, session)| session.touched.elapsed() >= SESSION_TTL)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            info!(
+                "abandoning upload {} after {:?} without a chunk",
+                id, SESSION_TTL
+            );
+            sessions.remove(&id);
+            fs::remove_file(self.partial(id)).unwrap_or(());
+        }
+    }
+
+    /// Removes partial files that haven't been written to for `SESSION_TTL` and belong to no
+    /// session, as when the server restarted mid-upload.
+    fn sweep(&self) {
+        let sessions = self.sessions.lock().unwrap();
+        let live = sessions
+            .keys()
+            .map(|id| self.partial(*id))
+            .collect::<Vec<_>>();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let stale = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map_or(false, |elapsed| elapsed >= SESSION_TTL);
+            let partial = path
+                .extension()
+                .map_or(false, |extension| extension == "part");
+            if stale && partial && !live.contains(&path) {
+                fs::remove_file(&path).unwrap_or(());
+            }
+        }
+    }
+
+    /// Where the received part of a chunked upload is kept until it completes.
+    fn partial(&self, id: UploadId) -> PathBuf {
+        self.dir.join(&format!("{}", id)).with_extension("part")
+    }
+}
+
+/// The hex-encoded SHA-256 digest of everything read from `reader`.
+fn digest<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// The maximum size of uploaded logs, in bytes.
+fn limit(request: &Request) -> u64 {
+    request.limits().get(LIMIT).unwrap_or(LIMIT_DEFAULT)
+}
+
+/// Writes everything read from `reader` to a temporary file, moving it to `path` only once it has
+/// been read in full, is no larger than `limit` bytes and (if a digest was supplied) matches the
+/// given SHA-256 digest. Nothing is left behind on failure.
+pub fn receive<R: Read, P: AsRef<Path>>(
+    reader: &mut R,
+    path: P,
+    limit: u64,
+    checksum: Option<&str>,
+) -> Result<(), UploadError> {
+    let partial = path.as_ref().with_extension("part");
+    let result = write_hashed(reader, &partial, limit).and_then(|digest| match checksum {
+        Some(checksum) if !checksum.trim().eq_ignore_ascii_case(&digest) => {
+            Err(UploadErrorKind::ChecksumMismatch.into())
+        }
+        _ => fs::rename(&partial, &path).map_err(UploadError::from),
+    });
+    if result.is_err() {
+        fs::remove_file(&partial).unwrap_or(());
+    }
+    result
+}
+
+/// Copies from `reader` to a new file at `path`, returning the hex-encoded SHA-256 digest of what
+/// was written.
+fn write_hashed<R: Read, P: AsRef<Path>>(
+    reader: &mut R,
+    path: P,
+    limit: u64,
+) -> Result<String, UploadError> {
+    let mut file = File::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut written = 0;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        written += read as u64;
+        if written > limit {
+            return Err(UploadErrorKind::TooLarge(limit).into());
+        }
+        hasher.input(&buf[..read]);
+        file.write_all(&buf[..read])?;
+    }
+    file.sync_all()?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch;
+
+    use cluster::host::Host;
+
+    use serde_json::json;
+
+    const ARCHIVE: &[u8] = b"not really a gzipped tarball";
+
+    fn begin(uploads: &Uploads, checksum: &str) -> UploadId {
+        let invocation = serde_json::from_value(json!(Uuid::new_v4())).unwrap();
+        let host = Host::new("alpha").id();
+        let size = ARCHIVE.len() as u64;
+        uploads
+            .begin(invocation, host, size, checksum, LIMIT_DEFAULT)
+            .unwrap()
+            .id()
+    }
+
+    fn incomplete(_: InvocationId, _: HostId, _: &Path) -> Result<(), Box<dyn Error>> {
+        panic!("the upload should not have completed");
+    }
+
+    #[test]
+    fn append_resumes_from_what_was_received() {
+        let dir = scratch();
+        let uploads = Uploads::new(&dir);
+        let metrics = Metrics::default();
+        let id = begin(&uploads, &digest(&mut &ARCHIVE[..]).unwrap());
+        let status = uploads
+            .append(id, 0, &ARCHIVE[..10], &metrics, incomplete)
+            .unwrap();
+        assert_eq!(status.offset(), 10);
+        assert!(!status.complete());
+        // A client that lost the response asks where to resume, and may resend what was received
+        assert_eq!(uploads.status(id).unwrap().offset(), 10);
+        let status = uploads
+            .append(id, 4, &ARCHIVE[4..16], &metrics, incomplete)
+            .unwrap();
+        assert_eq!(status.offset(), 16);
+        assert!(uploads
+            .append(id, 20, &ARCHIVE[20..], &metrics, incomplete)
+            .is_err());
+        let mut archive = None;
+        let status = uploads
+            .append(id, 16, &ARCHIVE[16..], &metrics, |_, _, path| {
+                archive = Some(path.to_path_buf());
+                Ok(())
+            })
+            .unwrap();
+        assert!(status.complete());
+        assert_eq!(fs::read(archive.unwrap()).unwrap(), ARCHIVE);
+        assert!(!uploads.partial(id).exists());
+        assert!(uploads.status(id).is_err());
+    }
+
+    #[test]
+    fn append_abandons_an_upload_that_fails_its_checksum() {
+        let dir = scratch();
+        let uploads = Uploads::new(&dir);
+        let metrics = Metrics::default();
+        let id = begin(&uploads, &digest(&mut &b"something else"[..]).unwrap());
+        match uploads.append(id, 0, ARCHIVE, &metrics, incomplete) {
+            Err(UploadError {
+                kind: UploadErrorKind::ChecksumMismatch,
+                ..
+            }) => (),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        // The client has to start again, and nothing is left behind
+        match uploads.status(id) {
+            Err(UploadError {
+                kind: UploadErrorKind::InvalidSession,
+                ..
+            }) => (),
+            other => panic!("expected the session to be gone, got {:?}", other),
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
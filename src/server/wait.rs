@@ -0,0 +1,54 @@
+use rocket::Config;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time;
+
+/// The longest any request is held open: a wait for the current invocation to change, a followed
+/// stream or a subscription to events. Clients re-request to carry on.
+pub const MAX_WAIT: time::Duration = time::Duration::from_secs(60);
+
+/// Limits how many requests may be held open at once, whether waiting for the current invocation
+/// to change, following a stream or subscribed to events. Each one occupies a worker, so without
+/// a limit a large cluster (or a few dashboards) could leave none free for heartbeats.
+pub struct Waiters {
+    limit: usize,
+    waiting: AtomicUsize,
+}
+
+/// A request holding one of the places to wait, which it gives up when dropped.
+pub struct Waiter<'a>(&'a AtomicUsize);
+
+impl Waiters {
+    /// Allows up to half of Rocket's workers to be spent waiting.
+    pub fn from_config(config: &Config) -> Waiters {
+        Waiters {
+            limit: config.workers as usize / 2,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a place to wait, if there is one free.
+    pub fn enter(&self) -> Option<Waiter> {
+        let mut waiting = self.waiting.load(Ordering::SeqCst);
+        loop {
+            if waiting >= self.limit {
+                return None;
+            }
+            match self.waiting.compare_exchange(
+                waiting,
+                waiting + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(Waiter(&self.waiting)),
+                Err(actual) => waiting = actual,
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Waiter<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+
+use nix::sys::statvfs;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::host::HostState;
+
+/// A snapshot of how busy a host is, as read from `/proc` when it sends a heartbeat.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Telemetry {
+    time: DateTime<Utc>,
+    /// The load averages over the last 1, 5 and 15 minutes.
+    load: [f64; 3],
+    /// In bytes.
+    memory_total: u64,
+    /// How much memory is available for new processes without swapping, in bytes.
+    memory_available: u64,
+    /// The free space on the filesystem holding the work directory, in bytes.
+    disk_free: u64,
+    /// The size of the filesystem holding the work directory, in bytes.
+    disk_total: u64,
+    /// The bytes received over every interface but loopback since boot.
+    network_received: u64,
+    /// The bytes sent over every interface but loopback since boot.
+    network_transmitted: u64,
+}
+
+/// What a host sends the server to show it is still alive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    state: HostState,
+    /// Sent with only some heartbeats, as sampling it isn't free.
+    #[serde(default)]
+    telemetry: Option<Telemetry>,
+}
+
+impl Telemetry {
+    /// Samples the host, measuring disk space on the filesystem holding the given directory.
+    pub fn sample<P: AsRef<Path>>(work_dir: P) -> io::Result<Telemetry> {
+        let (memory_total, memory_available) = memory()?;
+        let (disk_free, disk_total) = disk(work_dir)?;
+        let (network_received, network_transmitted) = network()?;
+        Ok(Telemetry {
+            time: Utc::now(),
+            load: load()?,
+            memory_total,
+            memory_available,
+            disk_free,
+            disk_total,
+            network_received,
+            network_transmitted,
+        })
+    }
+}
+
+impl Heartbeat {
+    pub fn new(state: HostState, telemetry: Option<Telemetry>) -> Heartbeat {
+        Heartbeat { state, telemetry }
+    }
+
+    pub fn state(&self) -> HostState {
+        self.state
+    }
+
+    pub fn into_telemetry(self) -> Option<Telemetry> {
+        self.telemetry
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("couldn't parse {}", what),
+    )
+}
+
+fn load() -> io::Result<[f64; 3]> {
+    let loadavg = fs::read_to_string("/proc/loadavg")?;
+    let mut fields = loadavg.split_whitespace();
+    let mut next = || {
+        fields
+            .next()
+            .and_then(|field| field.parse::<f64>().ok())
+            .ok_or_else(|| invalid("/proc/loadavg"))
+    };
+    Ok([next()?, next()?, next()?])
+}
+
+/// The total and available memory, in bytes.
+fn memory() -> io::Result<(u64, u64)> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line[name.len()..].split_whitespace().next())
+            .and_then(|kilobytes| kilobytes.parse::<u64>().ok())
+            .map(|kilobytes| kilobytes * 1024)
+            .ok_or_else(|| invalid("/proc/meminfo"))
+    };
+    Ok((field("MemTotal:")?, field("MemAvailable:")?))
+}
+
+/// The free and total space on the filesystem holding the given path (or, if it doesn't exist
+/// yet, its nearest existing ancestor), in bytes.
+fn disk<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let path = path
+        .as_ref()
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let stat = statvfs::statvfs(path).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let fragment = stat.fragment_size() as u64;
+    Ok((
+        stat.blocks_available() as u64 * fragment,
+        stat.blocks() as u64 * fragment,
+    ))
+}
+
+/// The bytes received and sent over every interface but loopback.
+fn network() -> io::Result<(u64, u64)> {
+    let dev = fs::read_to_string("/proc/net/dev")?;
+    let mut totals = (0, 0);
+    // The first two lines are headers
+    for line in dev.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+        let (interface, counters) = match (parts.next(), parts.next()) {
+            (Some(interface), Some(counters)) => (interface.trim(), counters),
+            _ => continue,
+        };
+        if interface == "lo" {
+            continue;
+        }
+        let counters = counters
+            .split_whitespace()
+            .map(|counter| counter.parse::<u64>().map_err(|_| invalid("/proc/net/dev")))
+            .collect::<io::Result<Vec<_>>>()?;
+        // Received bytes come first, and transmitted bytes after the eight received counters
+        if let (Some(received), Some(transmitted)) = (counters.get(0), counters.get(8)) {
+            totals.0 += received;
+            totals.1 += transmitted;
+        }
+    }
+    Ok(totals)
+}
@@ -0,0 +1,81 @@
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+use uuid::Uuid;
+
+/// Identifies a chunked upload of a log archive, which may be resumed until it completes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UploadId(Uuid);
+
+/// How far through a chunked upload the server is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadStatus {
+    id: UploadId,
+    /// How many bytes of the archive the server has received; the next chunk should start here.
+    offset: u64,
+    /// The size of the whole archive, in bytes.
+    size: u64,
+    /// Whether the whole archive has been received and verified.
+    complete: bool,
+}
+
+impl<'a> FromParam<'a> for UploadId {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        if let Ok(decoded) = param.url_decode() {
+            if let Ok(uuid) = Uuid::parse_str(&decoded) {
+                return Ok(UploadId(uuid));
+            }
+        }
+        Err(param)
+    }
+}
+
+impl UploadId {
+    pub fn generate() -> UploadId {
+        UploadId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for UploadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl UploadStatus {
+    pub fn new(id: UploadId, offset: u64, size: u64) -> UploadStatus {
+        UploadStatus {
+            id,
+            offset,
+            size,
+            complete: false,
+        }
+    }
+
+    pub fn completed(id: UploadId, size: u64) -> UploadStatus {
+        UploadStatus {
+            id,
+            offset: size,
+            size,
+            complete: true,
+        }
+    }
+
+    pub fn id(&self) -> UploadId {
+        self.id
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn complete(&self) -> bool {
+        self.complete
+    }
+}
@@ -6,6 +6,9 @@ var hosts = {};
 var invocations = {};
 var hostStates = {};
 var snackbar = [];
+var events = undefined;
+
+const PHASES = ["setup", "run", "teardown"];
 
 let View = class {
   constructor() {
@@ -52,7 +55,7 @@ let View = class {
       }
       this.reinvokeEvent = function() {
         displaySnackbar("attempting to reclone repository");
-        get("/api/reinvoke/" + invocation.id, function(response) {
+        send("POST", "/api/v2/invocations/" + invocation.id + "/reinvoke", null, function(response) {
           updateCurrent();
           viewing = response.id;
           invocation = response;
@@ -68,8 +71,16 @@ let View = class {
       if (invocation.id === current) {
         this.cancel.classList.remove("hidden");
         this.cancelEvent = function() {
+          var reason = window.prompt("reason for cancelling (optional)");
+          if (reason === null) {
+            return;
+          }
           displaySnackbar("attempting to cancel invocation");
-          get("/api/cancel", function() {
+          var body = { requester: "web" };
+          if (reason !== "") {
+            body.reason = reason;
+          }
+          send("DELETE", "/api/v2/current", body, function() {
             updateCurrent();
             document.getElementById("cancel").classList.add("hidden");
           }, function(err) {
@@ -94,22 +105,49 @@ let View = class {
         this.setup.appendChild(global);
         this.setup.appendChild(makeCommand(invocation.descriptor.command, invocation.descriptor.args));
       }
+      for (var phase of PHASES) {
+        if (invocation.descriptor[phase] != null) {
+          var header = document.createElement("h3");
+          header.appendChild(document.createTextNode("global " + phase));
+          this.setup.appendChild(header);
+          var descriptor = invocation.descriptor[phase];
+          this.setup.appendChild(makeCommand(descriptor.command, descriptor.args));
+        }
+      }
       var hostHeader = document.createElement("h3");
       hostHeader.appendChild(document.createTextNode("hosts"));
       this.setup.appendChild(hostHeader);
-      for (var host in invocation.descriptor.hosts) {
+      var participants = invocation.participants || [];
+      if (participants.length == 0) {
+        participants = Object.keys(invocation.descriptor.hosts);
+      }
+      var roles = invocation.roles || {};
+      for (var host of participants) {
         var hostname = document.createElement("p");
         hostname.classList.add("hostname");
         hostname.appendChild(document.createTextNode(host));
+        if (host in roles) {
+          hostname.appendChild(document.createTextNode(" (" + roles[host] + ")"));
+        }
         var state = document.createElement("a");
         hostStates[host] = state;
         updateHostState(host);
         hostname.appendChild(state);
         this.setup.appendChild(hostname);
-        var record = invocation.descriptor.hosts[host];
+        var record = (host in roles)
+            ? invocation.descriptor.roles[roles[host]]
+            : invocation.descriptor.hosts[host];
+        if (record === undefined) {
+          continue;
+        }
         if (record.command !== null) {
           this.setup.appendChild(makeCommand(record.command, record.args));
         }
+        for (var phase of PHASES) {
+          if (record[phase] != null) {
+            this.setup.appendChild(makeCommand(record[phase].command, record[phase].args, phase));
+          }
+        }
       }
     }
   }
@@ -121,6 +159,8 @@ let Host = class {
     this.hostname = record.hostname;
     this.state = record.state.desc;
     this.target = record.state.id;
+    this.cordoned = record.cordoned;
+    this.draining = record.draining;
     this.listing = undefined;
   }
 
@@ -135,6 +175,11 @@ let Host = class {
     var element = document.createElement("div");
     element.classList.add("hostname");
     element.appendChild(document.createTextNode(this.hostname));
+    if (this.draining) {
+      element.appendChild(document.createTextNode(" (draining)"));
+    } else if (this.cordoned) {
+      element.appendChild(document.createTextNode(" (cordoned)"));
+    }
     return element;
   }
 
@@ -172,6 +217,7 @@ let Invocation = class {
     this.url = record.url;
     this.commit = record.commit;
     this.start = record.start;
+    this.parameters = record.parameters || {};
     this.listing = undefined;
     if (this.name === null) {
       this.failed = true;
@@ -194,7 +240,12 @@ let Invocation = class {
       element.classList.add("unresolved");
       element.appendChild(document.createTextNode("(failed)")); 
     } else {
-      element.appendChild(document.createTextNode(this.name));
+      var name = this.name;
+      var parameters = Object.keys(this.parameters).map((key) => key + "=" + this.parameters[key]);
+      if (parameters.length > 0) {
+        name += " (" + parameters.join(", ") + ")";
+      }
+      element.appendChild(document.createTextNode(name));
     }
     return element;
   }
@@ -265,9 +316,22 @@ let Invocation = class {
 };
 
 function get(url, callback, err) {
+  send("GET", url, null, callback, err);
+}
+
+function send(method, url, body, callback, err) {
   var xhttp = new XMLHttpRequest();
-  xhttp.open("GET", url);
-  xhttp.send();
+  xhttp.open(method, url);
+  var token = window.localStorage.getItem("token");
+  if (token !== null) {
+    xhttp.setRequestHeader("Authorization", "Bearer " + token);
+  }
+  if (body !== null) {
+    xhttp.setRequestHeader("Content-Type", "application/json");
+    xhttp.send(JSON.stringify(body));
+  } else {
+    xhttp.send();
+  }
   xhttp.onreadystatechange = (e) => {
     if (xhttp.readyState === 4) {
       var response;
@@ -285,6 +349,13 @@ function get(url, callback, err) {
         if (!('msg' in response)) {
           response.msg = "an error occured";
         }
+        if (xhttp.status === 401) {
+          var entered = window.prompt("API token");
+          if (entered !== null && entered !== "") {
+            window.localStorage.setItem("token", entered);
+            response.msg = "token saved, please try again";
+          }
+        }
         err(response.msg);
       }
     }
@@ -452,7 +523,10 @@ function updateHostState(host) {
   }
 }
 
-function makeCommand(command, args) {
+function makeCommand(command, args, phase) {
+  if (args === null) {
+    args = [];
+  }
   for (arg of args) {
     if (arg.includes(" ")) {
       if (!arg.includes("\"")) {
@@ -464,6 +538,9 @@ function makeCommand(command, args) {
     command += " " + arg;
   }
   var element = document.createElement("pre");
+  if (phase !== undefined) {
+    command = phase + ": " + command;
+  }
   element.appendChild(document.createTextNode(command));
   return element;
 }
@@ -494,19 +571,52 @@ function displaySnackbar(msg) {
   snackbar.push(msg);
 }
 
-setInterval(updateCurrent, 500);
-setInterval(updateHosts, 500);
+const HOST_EVENTS = ["host_registered", "host_state", "host_disconnected", "host_cordoned"];
+const INVOCATION_EVENTS = ["invocation_created", "invocation_started", "invocation_cancelled",
+  "invocation_finished", "log_uploaded"];
+
+function subscribe() {
+  events = new EventSource("/api/v2/events");
+  for (const name of HOST_EVENTS) {
+    events.addEventListener(name, updateHosts);
+  }
+  for (const name of INVOCATION_EVENTS) {
+    events.addEventListener(name, function() {
+      updateInvocations(updateCurrent);
+    });
+  }
+  // Catch up on anything missed while the stream was down
+  events.addEventListener("open", function() {
+    updateCurrent();
+    updateHosts();
+  });
+  // The browser gives up if the server turns the stream away (e.g. because it is too busy)
+  events.addEventListener("error", function() {
+    if (events.readyState === EventSource.CLOSED) {
+      setTimeout(subscribe, 5000);
+    }
+  });
+}
+
+// Fall back to polling whenever the event stream is down (the browser reconnects by itself)
+setInterval(function() {
+  if (events === undefined || events.readyState !== EventSource.OPEN) {
+    updateCurrent();
+    updateHosts();
+  }
+}, 500);
 setInterval(updateSnackbar, 100);
 
 document.addEventListener('DOMContentLoaded', function() {
   view = new View();
   updateCurrent();
   updateHosts();
+  subscribe();
   document.getElementById("invoke_button").addEventListener("click", function() {
     displaySnackbar("attempting to clone repository");
-    var url = encodeURIComponent(document.getElementById('input').value).trim();
+    var url = document.getElementById('input').value.trim();
     if (url.length > 0) {
-      get("/api/invoke/" + url, function(response) {
+      send("POST", "/api/v2/invocations", { url: url }, function(response) {
         document.getElementById('input').value = '';
         viewing = response.id;
         invocation = response;